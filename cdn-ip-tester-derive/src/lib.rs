@@ -4,7 +4,8 @@ use proc_macro::TokenStream;
 use proc_macro2::TokenStream as TokenStream2;
 use quote::quote;
 use syn::parse_macro_input;
-use syn::DeriveInput;
+use syn::parse_quote;
+use syn::{Data, DataStruct, DeriveInput, Fields};
 
 #[proc_macro_derive(JsonLoadable)]
 pub fn derive_json_loadable(input: TokenStream) -> TokenStream {
@@ -30,6 +31,14 @@ pub fn derive_json_savable(input: TokenStream) -> TokenStream {
         .into()
 }
 
+#[proc_macro_derive(JsonSavablePretty)]
+pub fn derive_json_savable_pretty(input: TokenStream) -> TokenStream {
+    let mut input = parse_macro_input!(input as DeriveInput);
+    expand_derive_json_savable_pretty(&mut input)
+        .unwrap_or_else(to_compile_errors)
+        .into()
+}
+
 #[proc_macro_derive(TomlSavable)]
 pub fn derive_toml_savable(input: TokenStream) -> TokenStream {
     let mut input = parse_macro_input!(input as DeriveInput);
@@ -38,17 +47,75 @@ pub fn derive_toml_savable(input: TokenStream) -> TokenStream {
         .into()
 }
 
+#[proc_macro_derive(YamlLoadable)]
+pub fn derive_yaml_loadable(input: TokenStream) -> TokenStream {
+    let mut input = parse_macro_input!(input as DeriveInput);
+    expand_derive_yaml_loadable(&mut input)
+        .unwrap_or_else(to_compile_errors)
+        .into()
+}
+
+#[proc_macro_derive(YamlSavable)]
+pub fn derive_yaml_savable(input: TokenStream) -> TokenStream {
+    let mut input = parse_macro_input!(input as DeriveInput);
+    expand_derive_yaml_savable(&mut input)
+        .unwrap_or_else(to_compile_errors)
+        .into()
+}
+
+/// Binary formats can not go through `Loadable::from_str`, so `load` reads bytes directly.
+#[proc_macro_derive(BincodeLoadable)]
+pub fn derive_bincode_loadable(input: TokenStream) -> TokenStream {
+    let mut input = parse_macro_input!(input as DeriveInput);
+    expand_derive_bincode_loadable(&mut input)
+        .unwrap_or_else(to_compile_errors)
+        .into()
+}
+
+/// Binary formats can not go through `Savable::to_string`, so `to_bytes` is overridden.
+#[proc_macro_derive(BincodeSavable)]
+pub fn derive_bincode_savable(input: TokenStream) -> TokenStream {
+    let mut input = parse_macro_input!(input as DeriveInput);
+    expand_derive_bincode_savable(&mut input)
+        .unwrap_or_else(to_compile_errors)
+        .into()
+}
+
+/// Save a struct with named `Display` fields as a header row and a value row.
+#[proc_macro_derive(CsvSavable)]
+pub fn derive_csv_savable(input: TokenStream) -> TokenStream {
+    let mut input = parse_macro_input!(input as DeriveInput);
+    expand_derive_csv_savable(&mut input)
+        .unwrap_or_else(to_compile_errors)
+        .into()
+}
+
+/// Require every type parameter to implement `bound`, so derives also work on generic structs.
+fn add_where_bound(input: &mut DeriveInput, bound: TokenStream2) {
+    let type_idents: Vec<_> = input
+        .generics
+        .type_params()
+        .map(|param| param.ident.clone())
+        .collect();
+    let where_clause = input.generics.make_where_clause();
+    for ident in type_idents {
+        where_clause.predicates.push(parse_quote!(#ident: #bound));
+    }
+}
+
 fn to_compile_errors(errors: Vec<syn::Error>) -> proc_macro2::TokenStream {
     let compile_errors = errors.iter().map(syn::Error::to_compile_error);
     quote!(#(#compile_errors)*)
 }
 
 fn expand_derive_json_loadable(input: &mut DeriveInput) -> Result<TokenStream2, Vec<syn::Error>> {
+    add_where_bound(input, quote!(serde::de::DeserializeOwned));
     let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
     let gen = quote! {
-        impl crate::data::Loadable<Self> for #name {
-            fn from_str(s: &str) -> crate::error::Result<Self> {
-                Ok(serde_json::from_str(s).map_err(crate::error::DeserializedError::from)?)
+        impl #impl_generics ::cdn_ip_tester::data::Loadable<Self> for #name #ty_generics #where_clause {
+            fn from_str(s: &str) -> ::cdn_ip_tester::error::Result<Self> {
+                Ok(serde_json::from_str(s).map_err(::cdn_ip_tester::error::DeserializedError::from)?)
             }
         }
     };
@@ -56,11 +123,13 @@ fn expand_derive_json_loadable(input: &mut DeriveInput) -> Result<TokenStream2,
 }
 
 fn expand_derive_toml_loadable(input: &mut DeriveInput) -> Result<TokenStream2, Vec<syn::Error>> {
+    add_where_bound(input, quote!(serde::de::DeserializeOwned));
     let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
     let gen = quote! {
-        impl crate::data::Loadable<Self> for #name {
-            fn from_str(s: &str) -> crate::error::Result<Self> {
-                Ok(toml::from_str(s).map_err(crate::error::DeserializedError::from)?)
+        impl #impl_generics ::cdn_ip_tester::data::Loadable<Self> for #name #ty_generics #where_clause {
+            fn from_str(s: &str) -> ::cdn_ip_tester::error::Result<Self> {
+                Ok(toml::from_str(s).map_err(::cdn_ip_tester::error::DeserializedError::from)?)
             }
         }
     };
@@ -68,11 +137,13 @@ fn expand_derive_toml_loadable(input: &mut DeriveInput) -> Result<TokenStream2,
 }
 
 fn expand_derive_toml_savable(input: &mut DeriveInput) -> Result<TokenStream2, Vec<syn::Error>> {
+    add_where_bound(input, quote!(serde::Serialize));
     let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
     let gen = quote! {
-        impl crate::data::Savable for #name {
-            fn to_string(&self) -> crate::error::Result<String> {
-                Ok(toml::to_string(self).map_err(crate::error::SerializedError::from)?)
+        impl #impl_generics ::cdn_ip_tester::data::Savable for #name #ty_generics #where_clause {
+            fn to_string(&self) -> ::cdn_ip_tester::error::Result<String> {
+                Ok(toml::to_string(self).map_err(::cdn_ip_tester::error::SerializedError::from)?)
             }
         }
     };
@@ -80,11 +151,145 @@ fn expand_derive_toml_savable(input: &mut DeriveInput) -> Result<TokenStream2, V
 }
 
 fn expand_derive_json_savable(input: &mut DeriveInput) -> Result<TokenStream2, Vec<syn::Error>> {
+    add_where_bound(input, quote!(serde::Serialize));
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let gen = quote! {
+        impl #impl_generics ::cdn_ip_tester::data::Savable for #name #ty_generics #where_clause {
+            fn to_string(&self) -> ::cdn_ip_tester::error::Result<String> {
+                Ok(serde_json::to_string(self).map_err(::cdn_ip_tester::error::SerializedError::from)?)
+            }
+        }
+    };
+    Ok(gen)
+}
+
+fn expand_derive_json_savable_pretty(
+    input: &mut DeriveInput,
+) -> Result<TokenStream2, Vec<syn::Error>> {
+    add_where_bound(input, quote!(serde::Serialize));
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let gen = quote! {
+        impl #impl_generics ::cdn_ip_tester::data::Savable for #name #ty_generics #where_clause {
+            fn to_string(&self) -> ::cdn_ip_tester::error::Result<String> {
+                Ok(serde_json::to_string_pretty(self).map_err(::cdn_ip_tester::error::SerializedError::from)?)
+            }
+        }
+    };
+    Ok(gen)
+}
+
+fn expand_derive_yaml_loadable(input: &mut DeriveInput) -> Result<TokenStream2, Vec<syn::Error>> {
+    add_where_bound(input, quote!(serde::de::DeserializeOwned));
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let gen = quote! {
+        impl #impl_generics ::cdn_ip_tester::data::Loadable<Self> for #name #ty_generics #where_clause {
+            fn from_str(s: &str) -> ::cdn_ip_tester::error::Result<Self> {
+                Ok(serde_yaml::from_str(s).map_err(::cdn_ip_tester::error::DeserializedError::from)?)
+            }
+        }
+    };
+    Ok(gen)
+}
+
+fn expand_derive_yaml_savable(input: &mut DeriveInput) -> Result<TokenStream2, Vec<syn::Error>> {
+    add_where_bound(input, quote!(serde::Serialize));
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let gen = quote! {
+        impl #impl_generics ::cdn_ip_tester::data::Savable for #name #ty_generics #where_clause {
+            fn to_string(&self) -> ::cdn_ip_tester::error::Result<String> {
+                Ok(serde_yaml::to_string(self).map_err(::cdn_ip_tester::error::SerializedError::from)?)
+            }
+        }
+    };
+    Ok(gen)
+}
+
+fn expand_derive_bincode_loadable(input: &mut DeriveInput) -> Result<TokenStream2, Vec<syn::Error>> {
+    add_where_bound(input, quote!(serde::de::DeserializeOwned));
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let gen = quote! {
+        impl #impl_generics ::cdn_ip_tester::data::Loadable<Self> for #name #ty_generics #where_clause {
+            fn from_str(s: &str) -> ::cdn_ip_tester::error::Result<Self> {
+                let bytes = ::cdn_ip_tester::data::hex_decode(s)?;
+                Ok(bincode::deserialize(&bytes).map_err(::cdn_ip_tester::error::DeserializedError::from)?)
+            }
+
+            fn load<P: AsRef<std::path::Path>>(path: P) -> ::cdn_ip_tester::error::Result<Self> {
+                let bytes = std::fs::read(&path).map_err(|err| ::cdn_ip_tester::error::ErrorKind::fs(err, &path))?;
+                Ok(bincode::deserialize(&bytes).map_err(::cdn_ip_tester::error::DeserializedError::from)?)
+            }
+
+            async fn load_async<P: AsRef<std::path::Path>>(path: P) -> ::cdn_ip_tester::error::Result<Self> {
+                let bytes = tokio::fs::read(&path).await.map_err(|err| ::cdn_ip_tester::error::ErrorKind::fs(err, &path))?;
+                Ok(bincode::deserialize(&bytes).map_err(::cdn_ip_tester::error::DeserializedError::from)?)
+            }
+        }
+    };
+    Ok(gen)
+}
+
+fn expand_derive_bincode_savable(input: &mut DeriveInput) -> Result<TokenStream2, Vec<syn::Error>> {
+    add_where_bound(input, quote!(serde::Serialize));
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let gen = quote! {
+        impl #impl_generics ::cdn_ip_tester::data::Savable for #name #ty_generics #where_clause {
+            fn to_string(&self) -> ::cdn_ip_tester::error::Result<String> {
+                Ok(::cdn_ip_tester::data::hex_encode(&self.to_bytes()?))
+            }
+
+            fn to_bytes(&self) -> ::cdn_ip_tester::error::Result<Vec<u8>> {
+                Ok(bincode::serialize(self).map_err(::cdn_ip_tester::error::SerializedError::from)?)
+            }
+        }
+    };
+    Ok(gen)
+}
+
+fn expand_derive_csv_savable(input: &mut DeriveInput) -> Result<TokenStream2, Vec<syn::Error>> {
+    let field_idents: Vec<_> = match &input.data {
+        Data::Struct(DataStruct {
+            fields: Fields::Named(fields),
+            ..
+        }) => fields
+            .named
+            .iter()
+            .map(|field| field.ident.clone().unwrap())
+            .collect(),
+        _ => {
+            return Err(vec![syn::Error::new_spanned(
+                &input.ident,
+                "CsvSavable only supports structs with named fields",
+            )])
+        }
+    };
+    let header = field_idents
+        .iter()
+        .map(|ident| ident.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    add_where_bound(input, quote!(std::fmt::Display));
     let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
     let gen = quote! {
-        impl crate::data::Savable for #name {
-            fn to_string(&self) -> crate::error::Result<String> {
-                Ok(serde_json::to_string(self).map_err(crate::error::SerializedError::from)?)
+        impl #impl_generics ::cdn_ip_tester::data::Savable for #name #ty_generics #where_clause {
+            fn to_string(&self) -> ::cdn_ip_tester::error::Result<String> {
+                let values: Vec<String> = [#(self.#field_idents.to_string()),*]
+                    .into_iter()
+                    .map(|value| {
+                        if value.contains([',', '"', '\n']) {
+                            format!("\"{}\"", value.replace('"', "\"\""))
+                        } else {
+                            value
+                        }
+                    })
+                    .collect();
+                Ok(format!("{}\n{}\n", #header, values.join(",")))
             }
         }
     };
@@ -1,21 +1,52 @@
 use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
 use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use cidr::{IpCidr, IpInet};
+use clap::ValueEnum;
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use cdn_ip_tester_derive::{TomlLoadable, TomlSavable};
+use cdn_ip_tester_derive::{BincodeLoadable, BincodeSavable};
 
-use crate::data::{Loadable, Savable, Subnet};
-use crate::error::{DeserializedError, Result};
+use crate::data::{default_if_unreadable, hex_encode, Loadable, Savable, Subnet};
+use crate::error::{DeserializedError, ErrorKind, Result, SerializedError};
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RttResult {
-    cdn_rtt: u64,
     server_rtt: u64,
+    cdn_rtt: u64,
+    /// The best cdn url when testing multiple cdn urls.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cdn_url_used: Option<String>,
+    /// Standard deviation of the rtt samples, 0 if only one sample is taken.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    server_jitter: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    cdn_jitter: u64,
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+/// Population standard deviation of `rtts`, rounded to ms.
+pub fn rtt_jitter(rtts: &[u64]) -> u64 {
+    if rtts.len() < 2 {
+        return 0;
+    }
+    let mean = rtts.iter().sum::<u64>() as f64 / rtts.len() as f64;
+    let variance = rtts
+        .iter()
+        .map(|rtt| (*rtt as f64 - mean).powi(2))
+        .sum::<f64>()
+        / rtts.len() as f64;
+    variance.sqrt().round() as u64
 }
 
 impl Eq for RttResult {}
@@ -41,10 +72,117 @@ impl Ord for RttResult {
 }
 
 impl RttResult {
-    pub(crate) fn new(server_rtt: u64, cdn_rtt: u64) -> Self {
+    pub fn new(server_rtt: u64, cdn_rtt: u64) -> Self {
         Self {
             cdn_rtt,
             server_rtt,
+            cdn_url_used: None,
+            server_jitter: 0,
+            cdn_jitter: 0,
+        }
+    }
+
+    /// Use the min of the samples as rtt and their standard deviation as jitter.
+    pub fn from_samples(server_rtts: &[u64], cdn_rtts: &[u64]) -> Self {
+        Self::new(
+            *server_rtts.iter().min().unwrap(),
+            *cdn_rtts.iter().min().unwrap(),
+        )
+        .with_jitter(rtt_jitter(server_rtts), rtt_jitter(cdn_rtts))
+    }
+
+    pub fn with_jitter(mut self, server_jitter: u64, cdn_jitter: u64) -> Self {
+        self.server_jitter = server_jitter;
+        self.cdn_jitter = cdn_jitter;
+        self
+    }
+
+    pub fn with_cdn_url_used(mut self, cdn_url: String) -> Self {
+        self.cdn_url_used = Some(cdn_url);
+        self
+    }
+
+    pub fn combined_score(&self, server_weight: f64, cdn_weight: f64) -> f64 {
+        server_weight * self.server_rtt as f64 + cdn_weight * self.cdn_rtt as f64
+    }
+
+    pub fn server_rtt(&self) -> u64 {
+        self.server_rtt
+    }
+
+    pub fn cdn_rtt(&self) -> u64 {
+        self.cdn_rtt
+    }
+
+    pub fn cdn_url_used(&self) -> Option<&str> {
+        self.cdn_url_used.as_deref()
+    }
+
+    pub fn server_jitter(&self) -> u64 {
+        self.server_jitter
+    }
+
+    pub fn cdn_jitter(&self) -> u64 {
+        self.cdn_jitter
+    }
+}
+
+const CSV_HEADER: &str = "ip,network_length,server_rtt_ms,cdn_rtt_ms,cdn_url_used";
+/// Header of csv files written before `cdn_url_used` was added, still accepted by [`RttResults::from_csv`].
+const LEGACY_CSV_HEADER: &str = "ip,network_length,server_rtt_ms,cdn_rtt_ms";
+
+#[derive(Serialize, Deserialize)]
+struct RttResultJsonLine {
+    ip: String,
+    #[serde(flatten)]
+    rtt_result: RttResult,
+}
+
+#[derive(Serialize, Deserialize, ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    #[default]
+    #[value(alias = "server")]
+    ServerRtt,
+    #[value(alias = "cdn")]
+    CdnRtt,
+    Sum,
+    /// [`RttResult::combined_score`] with [`ScoreWeights`]
+    Score,
+}
+
+/// Weights used by [`SortKey::Score`], both should not be negative.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreWeights {
+    pub server_weight: f64,
+    pub cdn_weight: f64,
+}
+
+impl Default for ScoreWeights {
+    fn default() -> Self {
+        Self {
+            server_weight: 0.5,
+            cdn_weight: 0.5,
+        }
+    }
+}
+
+impl SortKey {
+    fn key(self, rtt_result: &RttResult, score_weights: ScoreWeights) -> (u64, u64) {
+        match self {
+            SortKey::ServerRtt => (rtt_result.server_rtt, rtt_result.cdn_rtt),
+            SortKey::CdnRtt => (rtt_result.cdn_rtt, rtt_result.server_rtt),
+            SortKey::Sum => (
+                rtt_result.server_rtt + rtt_result.cdn_rtt,
+                rtt_result.server_rtt,
+            ),
+            // keep 3 decimal places so that fractional weights still affect the order
+            SortKey::Score => (
+                (rtt_result.combined_score(score_weights.server_weight, score_weights.cdn_weight)
+                    * 1000.0)
+                    .round() as u64,
+                rtt_result.server_rtt,
+            ),
         }
     }
 }
@@ -54,6 +192,8 @@ pub struct RttResults {
     res: HashMap<IpInet, RttResult>,
     sorted_res_keys: Vec<IpInet>,
     tmp_key_set: HashSet<IpInet>,
+    sort_key: SortKey,
+    score_weights: ScoreWeights,
 }
 
 impl RttResults {
@@ -61,37 +201,249 @@ impl RttResults {
         self.res.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.res.is_empty()
+    }
+
+    /// Iterate the committed results from best to worst.
+    pub fn iter(&self) -> impl Iterator<Item = (&IpInet, &RttResult)> {
+        self.sorted_res_keys
+            .iter()
+            .map(|ip_inet| (ip_inet, self.res.get(ip_inet).unwrap()))
+    }
+
+    pub fn get(&self, ip_inet: &IpInet) -> Option<&RttResult> {
+        self.res.get(ip_inet)
+    }
+
+    pub fn get_top_n(&self, n: usize) -> Vec<(&IpInet, &RttResult)> {
+        self.iter().take(n).collect()
+    }
+
+    /// Build a single host subnet for every ip, used to re-test known ips.
+    pub fn from_ips(ips: &[IpInet]) -> Vec<Subnet> {
+        ips.iter()
+            .map(|ip_inet| Subnet::new_host(ip_inet.address()))
+            .collect()
+    }
+
+    pub fn filter_by_rtt(&self, max_server_rtt: Option<u64>, max_cdn_rtt: Option<u64>) -> Self {
+        let mut ret = Self {
+            sort_key: self.sort_key,
+            score_weights: self.score_weights,
+            ..Default::default()
+        };
+
+        for ip_inet in &self.sorted_res_keys {
+            let rtt_result = self.res.get(ip_inet).unwrap();
+            if max_server_rtt.is_none_or(|max_server_rtt| rtt_result.server_rtt <= max_server_rtt)
+                && max_cdn_rtt.is_none_or(|max_cdn_rtt| rtt_result.cdn_rtt <= max_cdn_rtt)
+            {
+                ret.res.insert(*ip_inet, rtt_result.clone());
+                ret.sorted_res_keys.push(*ip_inet);
+            }
+        }
+        ret
+    }
+
+    /// Only keep the best `n` results, should be called after [`RttResults::commit`].
+    pub fn truncate(&mut self, n: usize) {
+        for ip_inet in self.sorted_res_keys.iter().skip(n) {
+            self.res.remove(ip_inet);
+        }
+        self.sorted_res_keys.truncate(n);
+    }
+
+    /// Change the sort key and resort the committed results.
+    pub fn sort_by_key(&mut self, sort_key: SortKey) {
+        self.sort_key = sort_key;
+        self.resort();
+    }
+
+    pub fn set_score_weights(&mut self, score_weights: ScoreWeights) {
+        self.score_weights = score_weights;
+        self.resort();
+    }
+
+    fn key(&self, rtt_result: &RttResult) -> (u64, u64) {
+        self.sort_key.key(rtt_result, self.score_weights)
+    }
+
+    /// Stable sort the committed results, ties keep their current order.
+    pub fn resort(&mut self) {
+        let res = &self.res;
+        let sort_key = self.sort_key;
+        let score_weights = self.score_weights;
+        self.sorted_res_keys
+            .sort_by_key(|ip_inet| sort_key.key(res.get(ip_inet).unwrap(), score_weights));
+    }
+
     pub fn add_result(&mut self, ip_inet: IpInet, rtt_result: RttResult) {
         self.tmp_key_set.insert(ip_inet);
         // 永远用最新的结果进行覆盖
         self.res.insert(ip_inet, rtt_result);
     }
 
+    /// Same as [`RttResults::add_result`] but the old result is kept if it is better.
+    pub fn add_better_result(&mut self, ip_inet: IpInet, rtt_result: RttResult) {
+        if let Some(old_rtt_result) = self.res.get(&ip_inet) {
+            if self.key(old_rtt_result) <= self.key(&rtt_result) {
+                return;
+            }
+        }
+        self.add_result(ip_inet, rtt_result);
+    }
+
+    /// Remove the result of `ip_inet`, the order of other results is kept.
+    pub fn remove_result(&mut self, ip_inet: &IpInet) -> Option<RttResult> {
+        self.tmp_key_set.remove(ip_inet);
+        self.sorted_res_keys.retain(|key| key != ip_inet);
+        self.res.remove(ip_inet)
+    }
+
+    /// Same as [`RttResults::add_result`] but the committed position of `ip_inet` is dropped
+    /// first, so the new result is sorted again by [`RttResults::commit`].
+    pub fn replace_result(&mut self, ip_inet: IpInet, rtt_result: RttResult) {
+        self.remove_result(&ip_inet);
+        self.add_result(ip_inet, rtt_result);
+    }
+
+    /// Merge `other` into self, keep the better result if an ip appears in both.
+    /// [`RttResults::commit`] must be called after merging.
+    pub fn merge(&mut self, other: RttResults) {
+        for (ip_inet, rtt_result) in other.res {
+            self.add_better_result(ip_inet, rtt_result);
+        }
+    }
+
     fn from_string_list(s: &Vec<String>) -> Result<Self> {
         lazy_static! {
             static ref RE_RTT_RESULT_MATCH: Regex =
-                Regex::new(r"^ip: (.{2,45}/\d+), server_rtt: (\d+), cdn_rtt: (\d+)$").unwrap();
+                Regex::new(r"^ip: (.{2,45}/\d+), server_rtt: (\d+), cdn_rtt: (\d+)(?:, server_jitter: (\d+), cdn_jitter: (\d+))?(?:, cdn_url: (.+))?$").unwrap();
         }
         let mut ret = Self::default();
 
         for line in s {
+            // section headers of `to_grouped_string`
+            if line.starts_with("===") && line.ends_with("===") {
+                continue;
+            }
             let res = RE_RTT_RESULT_MATCH.captures(line);
             if let Some(res) = res {
                 let ip_inet = IpInet::from_str(&res[1]).map_err(DeserializedError::from)?;
-                ret.res.insert(
-                    ip_inet,
-                    RttResult::new(
-                        u64::from_str(&res[2]).map_err(DeserializedError::from)?,
-                        u64::from_str(&res[3]).map_err(DeserializedError::from)?,
-                    ),
+                let mut rtt_result = RttResult::new(
+                    u64::from_str(&res[2]).map_err(DeserializedError::from)?,
+                    u64::from_str(&res[3]).map_err(DeserializedError::from)?,
                 );
+                if let (Some(server_jitter), Some(cdn_jitter)) = (res.get(4), res.get(5)) {
+                    rtt_result = rtt_result.with_jitter(
+                        u64::from_str(server_jitter.as_str()).map_err(DeserializedError::from)?,
+                        u64::from_str(cdn_jitter.as_str()).map_err(DeserializedError::from)?,
+                    );
+                }
+                if let Some(cdn_url) = res.get(6) {
+                    rtt_result = rtt_result.with_cdn_url_used(cdn_url.as_str().into());
+                }
+                ret.res.insert(ip_inet, rtt_result);
                 ret.sorted_res_keys.push(ip_inet);
             } else {
                 return Err(DeserializedError::regex(line.clone(), &RE_RTT_RESULT_MATCH))?;
             }
         }
-        ret.sorted_res_keys
-            .sort_by_key(|ip_inet| ret.res.get(ip_inet).unwrap());
+        ret.resort();
+        Ok(ret)
+    }
+
+    pub fn from_csv(s: &str) -> Result<Self> {
+        let mut ret = Self::default();
+        let mut lines = s
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty());
+
+        let mut column_count = 5;
+        if let Some(header) = lines.next() {
+            if header == LEGACY_CSV_HEADER {
+                column_count = 4;
+            } else if header != CSV_HEADER {
+                return Err(DeserializedError::custom(
+                    format!("csv header must be {CSV_HEADER:?}, but got {header:?}").as_str(),
+                ))?;
+            }
+        }
+        for line in lines {
+            // the cdn url is the last column, it may contain commas
+            let columns: Vec<&str> = line.splitn(column_count, ',').collect();
+            if columns.len() != column_count {
+                return Err(DeserializedError::custom(
+                    format!("csv line must have {column_count} columns: {line:?}").as_str(),
+                ))?;
+            }
+            let ip_inet = IpInet::from_str(format!("{}/{}", columns[0], columns[1]).as_str())
+                .map_err(DeserializedError::from)?;
+            let mut rtt_result = RttResult::new(
+                u64::from_str(columns[2]).map_err(DeserializedError::from)?,
+                u64::from_str(columns[3]).map_err(DeserializedError::from)?,
+            );
+            if let Some(cdn_url) = columns.get(4).filter(|cdn_url| !cdn_url.is_empty()) {
+                rtt_result = rtt_result.with_cdn_url_used(cdn_url.to_string());
+            }
+            ret.res.insert(ip_inet, rtt_result);
+            ret.sorted_res_keys.push(ip_inet);
+        }
+        ret.resort();
+        Ok(ret)
+    }
+
+    pub fn to_csv(&self) -> Result<String> {
+        let mut ret = format!("{CSV_HEADER}\n");
+
+        for ip_inet in &self.sorted_res_keys {
+            let rtt_result = self.res.get(ip_inet).unwrap();
+            ret.push_str(
+                format!(
+                    "{},{},{},{},{}\n",
+                    ip_inet.address(),
+                    ip_inet.network_length(),
+                    rtt_result.server_rtt,
+                    rtt_result.cdn_rtt,
+                    rtt_result.cdn_url_used.as_deref().unwrap_or_default()
+                )
+                .as_str(),
+            );
+        }
+        Ok(ret)
+    }
+
+    pub fn from_jsonl(s: &str) -> Result<Self> {
+        let mut ret = Self::default();
+
+        for line in s.lines().map(|line| line.trim()).filter(|line| !line.is_empty()) {
+            let json_line: RttResultJsonLine =
+                serde_json::from_str(line).map_err(DeserializedError::from)?;
+            let ip_inet = IpInet::from_str(&json_line.ip).map_err(DeserializedError::from)?;
+            ret.res.insert(ip_inet, json_line.rtt_result);
+            ret.sorted_res_keys.push(ip_inet);
+        }
+        ret.resort();
+        Ok(ret)
+    }
+
+    pub fn to_jsonl(&self) -> Result<String> {
+        let mut ret = String::new();
+
+        for ip_inet in &self.sorted_res_keys {
+            let json_line = RttResultJsonLine {
+                ip: ip_inet.to_string(),
+                rtt_result: self.res.get(ip_inet).unwrap().clone(),
+            };
+            ret.push_str(
+                serde_json::to_string(&json_line)
+                    .map_err(SerializedError::from)?
+                    .as_str(),
+            );
+            ret.push('\n');
+        }
         Ok(ret)
     }
 
@@ -102,7 +454,7 @@ impl RttResults {
             return;
         }
         let mut buf: Vec<IpInet> = self.tmp_key_set.iter().copied().collect();
-        buf.sort_by_key(|ip_inet| self.res.get(ip_inet).unwrap());
+        buf.sort_by_key(|ip_inet| self.key(self.res.get(ip_inet).unwrap()));
 
         let mut i = 0_usize;
         let mut j = 0_usize;
@@ -127,7 +479,9 @@ impl RttResults {
             let tmp_res_data = res_data.unwrap();
             let tmp_buf_data = buf_data.unwrap();
 
-            if self.res.get(&tmp_res_data).unwrap() < self.res.get(&tmp_buf_data).unwrap() {
+            if self.key(self.res.get(&tmp_res_data).unwrap())
+                < self.key(self.res.get(&tmp_buf_data).unwrap())
+            {
                 i += 1;
                 res_data = self.sorted_res_keys.get(i).cloned();
                 if !self.tmp_key_set.contains(&tmp_res_data) {
@@ -168,26 +522,321 @@ impl Loadable<Self> for RttResults {
     }
 }
 
-impl Savable for RttResults {
-    fn to_string(&self) -> Result<String> {
+impl RttResults {
+    /// Text format with a `=== 1.2.3.0/24 ===` header before the results of each subnet, subnets
+    /// are sorted by their best `server_rtt`. An ip belongs to the first subnet which contains it,
+    /// the ips not in any subnet are written under `=== other ===`.
+    pub fn to_grouped_string(&self, subnets: &[Subnet]) -> Result<String> {
+        let mut groups: Vec<(String, Vec<IpInet>)> = subnets
+            .iter()
+            .map(|subnet| (subnet.cidr.to_string(), Vec::new()))
+            .collect();
+        let mut other_ips = Vec::new();
+        for ip_inet in &self.sorted_res_keys {
+            match subnets
+                .iter()
+                .position(|subnet| subnet.contains(ip_inet.address()))
+            {
+                Some(idx) => groups[idx].1.push(*ip_inet),
+                None => other_ips.push(*ip_inet),
+            }
+        }
+        groups.retain(|(_, ips)| !ips.is_empty());
+        groups.sort_by_key(|(_, ips)| ips.iter().map(|ip_inet| self.res[ip_inet].server_rtt).min());
+        if !other_ips.is_empty() {
+            groups.push(("other".into(), other_ips));
+        }
+
         let mut ret = String::new();
+        for (name, ips) in groups {
+            ret.push_str(format!("=== {name} ===\n").as_str());
+            for ip_inet in ips {
+                self.push_line(&mut ret, &ip_inet);
+            }
+        }
+        Ok(ret)
+    }
 
-        for ip_inet in &self.sorted_res_keys {
-            let rtt_result = self.res.get(ip_inet).unwrap();
+    fn push_line(&self, ret: &mut String, ip_inet: &IpInet) {
+        let rtt_result = self.res.get(ip_inet).unwrap();
+        ret.push_str(
+            format!(
+                "ip: {ip_inet}, server_rtt: {}, cdn_rtt: {}",
+                rtt_result.server_rtt, rtt_result.cdn_rtt
+            )
+            .as_str(),
+        );
+        // only written when multiple rtt samples are taken
+        if rtt_result.server_jitter != 0 || rtt_result.cdn_jitter != 0 {
             ret.push_str(
                 format!(
-                    "ip: {ip_inet}, server_rtt: {}, cdn_rtt: {}\n",
-                    rtt_result.server_rtt, rtt_result.cdn_rtt
+                    ", server_jitter: {}, cdn_jitter: {}",
+                    rtt_result.server_jitter, rtt_result.cdn_jitter
                 )
                 .as_str(),
             );
         }
+        if let Some(cdn_url) = &rtt_result.cdn_url_used {
+            ret.push_str(format!(", cdn_url: {cdn_url}").as_str());
+        }
+        ret.push('\n');
+    }
+}
+
+impl Savable for RttResults {
+    fn to_string(&self) -> Result<String> {
+        let mut ret = String::new();
+        for ip_inet in &self.sorted_res_keys {
+            self.push_line(&mut ret, ip_inet);
+        }
         Ok(ret)
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, TomlLoadable, TomlSavable)]
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Csv,
+    Jsonl,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Text => "txt",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Jsonl => "jsonl",
+        }
+    }
+
+    pub fn parse(self, s: &str) -> Result<RttResults> {
+        match self {
+            OutputFormat::Text => RttResults::from_str(s),
+            OutputFormat::Csv => RttResults::from_csv(s),
+            OutputFormat::Jsonl => RttResults::from_jsonl(s),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(self, path: P) -> Result<RttResults> {
+        self.parse(
+            fs::read_to_string(&path)
+                .map_err(|err| ErrorKind::fs(err, &path))?
+                .as_str(),
+        )
+    }
+
+    pub async fn load_async<P: AsRef<Path>>(self, path: P) -> Result<RttResults> {
+        self.parse(
+            tokio::fs::read_to_string(&path)
+                .await
+                .map_err(|err| ErrorKind::fs(err, &path))?
+                .as_str(),
+        )
+    }
+
+    /// Same as [`OutputFormat::load_async`] but returns empty results if the file can not be
+    /// read.
+    pub async fn load_or_default_async<P: AsRef<Path>>(self, path: P) -> Result<RttResults> {
+        default_if_unreadable(self.load_async(&path).await, path)
+    }
+}
+
+/// Save [`RttResults`] in the selected [`OutputFormat`].
+pub struct FormattedRttResults<'a> {
+    rtt_results: &'a RttResults,
+    format: OutputFormat,
+}
+
+impl Savable for FormattedRttResults<'_> {
+    fn to_string(&self) -> Result<String> {
+        match self.format {
+            OutputFormat::Text => self.rtt_results.to_string(),
+            OutputFormat::Csv => self.rtt_results.to_csv(),
+            OutputFormat::Jsonl => self.rtt_results.to_jsonl(),
+        }
+    }
+}
+
+impl RttResults {
+    pub fn with_format(&self, format: OutputFormat) -> FormattedRttResults<'_> {
+        FormattedRttResults {
+            rtt_results: self,
+            format,
+        }
+    }
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[derive(Serialize, Deserialize, Debug, BincodeLoadable, BincodeSavable)]
+#[serde(default)]
 pub struct RttResultCache {
     pub current_subnet: usize,
     pub current_subnet_start: usize,
+    /// Unix timestamp
+    pub cache_created_at: u64,
+    pub cache_ttl_hours: u64,
+    /// Seed of the random ip selection, resumed runs use the same seed.
+    pub seed: Option<u64>,
+    /// Only every `density`th ip of each subnet is tested, resumed runs use the same density.
+    pub density: usize,
+    /// See [`crate::data::Subnet::strided_index`], resumed runs use the same stride.
+    pub stride: usize,
+    /// See [`subnet_file_hash`], empty for caches created before the hash is stored.
+    pub subnet_file_hash: String,
+    /// Unix timestamp of the first save, 0 if never saved
+    pub started_at: u64,
+    /// Unix timestamp of the last save
+    pub updated_at: u64,
+    /// Test time accumulated across sessions
+    pub elapsed_secs: u64,
+    /// Number of consecutive batches in which every tested ip of the subnet failed, indexed by
+    /// subnet idx
+    pub subnet_failure_counts: Vec<usize>,
+    /// Increased by one for every new run, resumed runs keep the same id
+    pub run_id: u64,
+    /// Consecutive ips taken in each subnet visit, resumed runs use the same value.
+    pub ips_per_subnet: usize,
+}
+
+impl Default for RttResultCache {
+    fn default() -> Self {
+        Self {
+            current_subnet: 0,
+            current_subnet_start: 0,
+            cache_created_at: unix_timestamp(),
+            cache_ttl_hours: 24,
+            seed: None,
+            density: 1,
+            stride: 1,
+            subnet_file_hash: String::new(),
+            started_at: 0,
+            updated_at: 0,
+            elapsed_secs: 0,
+            subnet_failure_counts: Vec::new(),
+            run_id: 1,
+            ips_per_subnet: 1,
+        }
+    }
+}
+
+impl RttResultCache {
+    pub fn is_expired(&self) -> bool {
+        unix_timestamp().saturating_sub(self.cache_created_at)
+            > self.cache_ttl_hours.saturating_mul(3600)
+    }
+
+    /// Parse the toml cache written by older versions.
+    pub fn from_toml(s: &str) -> Result<Self> {
+        Ok(toml::from_str(s).map_err(DeserializedError::from)?)
+    }
+
+    /// Same as [`Loadable::load_or_default_async`] but loads the toml cache at `legacy_path`
+    /// written by older versions if `path` does not exist yet.
+    pub async fn load_or_legacy_async<P: AsRef<Path>, L: AsRef<Path>>(
+        path: P,
+        legacy_path: L,
+    ) -> Result<Self> {
+        if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            if let Ok(s) = tokio::fs::read_to_string(&legacy_path).await {
+                return Self::from_toml(&s);
+            }
+        }
+        Self::load_or_default_async(path).await
+    }
+
+    /// A new cache for the next run, only `run_id` is carried over.
+    pub fn next_run(&self) -> Self {
+        Self {
+            run_id: self.run_id + 1,
+            ..Default::default()
+        }
+    }
+
+    /// Update the timestamps and add the time since `last_touch` to `elapsed_secs` before saving,
+    /// `last_touch` only advances by whole seconds so that no time is lost.
+    pub fn touch(&mut self, last_touch: &mut Instant) {
+        let now = unix_timestamp();
+        if self.started_at == 0 {
+            self.started_at = now;
+        }
+        self.updated_at = now;
+        let elapsed_secs = last_touch.elapsed().as_secs();
+        *last_touch += Duration::from_secs(elapsed_secs);
+        self.elapsed_secs += elapsed_secs;
+    }
+
+    /// The subnet indices of the cache are meaningless once the subnet file changes, an unknown
+    /// hash is treated as unchanged.
+    pub fn is_subnet_file_changed(&self, subnet_file_hash: &str) -> bool {
+        !self.subnet_file_hash.is_empty()
+            && !subnet_file_hash.is_empty()
+            && self.subnet_file_hash != subnet_file_hash
+    }
+
+    /// Check that the cache was created for the same subnets of `ip_file` before resuming from
+    /// it. `current_subnet_start` is compared with the size of the current subnet only if the
+    /// hash is unknown, a smaller subnet is valid otherwise as it is skipped in the later rounds.
+    pub fn check_resume(
+        &self,
+        subnets: &[Subnet],
+        ip_file: &str,
+        subnet_file_hash: &str,
+    ) -> Result<()> {
+        let hint = "pass --no-cache to start a new run or --no-resume-check to resume anyway";
+        if self.is_subnet_file_changed(subnet_file_hash) {
+            Err(ErrorKind::config(format!(
+                "{ip_file} changed since the rtt result cache was created, {hint}"
+            )))?
+        }
+        let Some(subnet) = subnets.get(self.current_subnet) else {
+            return Err(ErrorKind::config(format!(
+                "current_subnet {} of the rtt result cache is out of the {} subnets of {ip_file}, {hint}",
+                self.current_subnet,
+                subnets.len()
+            )))?;
+        };
+        let hash_unknown = self.subnet_file_hash.is_empty() || subnet_file_hash.is_empty();
+        if hash_unknown && self.current_subnet_start >= subnet.len() {
+            Err(ErrorKind::config(format!(
+                "current_subnet_start {} of the rtt result cache is out of subnet {} of {ip_file}, {hint}",
+                self.current_subnet_start, subnet.cidr
+            )))?
+        }
+        Ok(())
+    }
+
+    /// Count a batch in which every tested ip of the subnet failed, or reset the count if any
+    /// succeeded. Returns the new failure count.
+    pub fn record_subnet_batch(&mut self, subnet_idx: usize, all_failed: bool) -> usize {
+        if self.subnet_failure_counts.len() <= subnet_idx {
+            self.subnet_failure_counts.resize(subnet_idx + 1, 0);
+        }
+        let failure_count = &mut self.subnet_failure_counts[subnet_idx];
+        if all_failed {
+            *failure_count += 1;
+        } else {
+            *failure_count = 0;
+        }
+        *failure_count
+    }
+
+    /// Whether the failure count of the subnet exceeds `threshold`.
+    pub fn is_subnet_tripped(&self, subnet_idx: usize, threshold: usize) -> bool {
+        self.subnet_failure_counts
+            .get(subnet_idx)
+            .is_some_and(|failure_count| *failure_count > threshold)
+    }
+}
+
+/// Hex encoded SHA-256 of the subnet file.
+pub fn subnet_file_hash<P: AsRef<Path>>(path: P) -> Result<String> {
+    let contents = fs::read(&path).map_err(|err| ErrorKind::fs(err, &path))?;
+    Ok(hex_encode(&Sha256::digest(contents)))
 }
@@ -1,8 +1,102 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, fs};
+
+use regex::Regex;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::Method;
 use serde::{Deserialize, Serialize};
+use url::Url;
 
 use cdn_ip_tester_derive::{TomlLoadable, TomlSavable};
 
+use crate::cache::{ScoreWeights, SortKey};
+use crate::error::{DeserializedError, ErrorKind, Result};
+
+/// Upper bound of [`Config::retry_count`].
+pub const MAX_RETRY_COUNT: u32 = 10;
+
+/// Annotated version of [`Config::default`], toml serializer can not write comments.
+pub const DEFAULT_CONFIG_TOML: &str = concat!(
+    r#"# 本机监听的最小端口值，端口范围为 [port_base, port_base + max_connection_count)，不能超过 65535
+port_base = 31000
+# 同时测试的最大连接数，需大于 0
+max_connection_count = 50
+# 远程 url，需为 http 或 https
+server_url = "http://127.0.0.1/"
+# cdn url，需为 http 或 https，为空表示直接访问 cdn 的 ip
+cdn_url = ""
+# 绑定的本机 ip
+listen_ip = "127.0.0.2"
+# 最大延迟（毫秒），需大于 0，超时后的结果会被自动丢弃
+max_rtt = 1000
+# {server_url} 的返回结果需要包含 {server_res_body}，为空则表示忽略返回结果检查
+server_res_body = ""
+# {cdn_url} 的返回结果需要包含 {cdn_res_body}，为空则表示忽略返回结果检查
+cdn_res_body = "error code: 1003"
+# 子网内最多选取多少个 ip
+max_subnet_len = 256
+# 严格模式下单个批次允许的最大失败百分比，范围为 [0, 100]
+strict_max_failure_pct = 100.0
+# 结果排序方式，可选 server_rtt，cdn_rtt，sum（两者之和），score（两者的加权和）
+sort_by = "server_rtt"
+# 网络错误时的重试次数，body 不匹配不会重试，不能超过 10
+retry_count = 2
+# 第 n 次重试前等待 retry_delay_ms * 2^n 毫秒，最多等待 30 秒
+retry_delay_ms = 200
+# 同时运行的 sing-box 进程数，需大于 0，每批 ip 会平均分配给各个进程
+parallel_sing_box = 1
+# sing-box 可执行文件的路径
+sing_box_path = "./sing-box"
+# 等待 sing-box 启动的最长时间（秒），超时后会结束 sing-box 进程并报错
+startup_timeout_secs = 10
+# 追加在 `sing-box run -c <config>` 之后的参数，如 ["--disable-color"]
+sing_box_extra_args = []
+# 多个 cdn url，不为空时会覆盖 cdn_url，每个 ip 使用所有 url 中最好的结果
+cdn_urls = []
+# sort_by = "score" 时 server_rtt 的权重，不能为负数，其他排序方式下会被忽略并输出警告
+score_server_weight = 0.5
+# sort_by = "score" 时 cdn_rtt 的权重，不能为负数
+score_cdn_weight = 0.5
+# 不校验 https cdn url 的证书，直接访问 cdn ip 时证书通常与 ip 不匹配
+tls_skip_verify = false
+# cdn 请求额外携带的 header，如 { Host = "example.com" }
+cdn_request_headers = {}
+# cdn 请求方法，可选 GET 与 HEAD，HEAD 时不检查 cdn_res_body，只检查状态码
+cdn_request_method = "GET"
+# {cdn_url} 返回的状态码需在 [cdn_expected_status_min, cdn_expected_status_max] 范围内
+cdn_expected_status_min = 200
+cdn_expected_status_max = 299
+# {server_url} 返回的状态码需在 [server_expected_status_min, server_expected_status_max] 范围内
+server_expected_status_min = 200
+server_expected_status_max = 299
+# 将 cdn_res_body 作为正则表达式匹配返回结果
+cdn_res_body_regex = false
+# 将 server_res_body 作为正则表达式匹配返回结果
+server_res_body_regex = false
+# cdn 与 server 请求的 User-Agent
+user_agent = "cdn-ip-tester/"#,
+    env!("CARGO_PKG_VERSION"),
+    r#""
+# 建立连接的超时时间（毫秒），需大于 0，max_rtt 为整个请求的超时时间，默认与 max_rtt 相同
+# connection_timeout_ms = 1000
+# 每个 ip 顺序测试的次数，需大于 0，取最小值作为 rtt，大于 1 时同时记录标准差作为抖动
+rtt_samples = 1
+# 测试完成后将结果摘要以 json POST 到该 url，默认不发送
+# webhook_url = "https://example.com/webhook"
+# sing-box 使用的 dns 服务器，如 "tls://1.1.1.1"，设置后会覆盖模板中的 dns 配置，默认使用模板中的配置
+# dns_server = "tls://1.1.1.1"
+"#
+);
+
+/// Missing fields fall back to [`Config::default`], so older `ip-tester.toml` keep working.
 #[derive(Serialize, Deserialize, Clone, TomlLoadable, TomlSavable)]
+#[serde(default)]
 pub struct Config {
     pub port_base: u16,
     pub max_connection_count: usize,
@@ -12,5 +106,389 @@ pub struct Config {
     pub max_rtt: u64,
     pub server_res_body: String,
     pub cdn_res_body: String,
+    /// Max number of ips tested in each subnet.
     pub max_subnet_len: usize,
+    pub strict_max_failure_pct: f64,
+    pub sort_by: SortKey,
+    /// Retry times when a request fails because of network error.
+    pub retry_count: u32,
+    /// The n-th retry waits `retry_delay_ms * 2^n` ms, see [`crate::rtt::retry_delay`].
+    pub retry_delay_ms: u64,
+    /// Number of sing-box processes used to test a batch.
+    pub parallel_sing_box: usize,
+    pub sing_box_path: String,
+    /// Max seconds to wait for sing-box to start.
+    pub startup_timeout_secs: u64,
+    /// Appended to `sing-box run -c <config>`.
+    pub sing_box_extra_args: Vec<String>,
+    /// Overrides `cdn_url` when not empty, the best result of all urls is used.
+    pub cdn_urls: Vec<String>,
+    /// Weights used when sorting by [`SortKey::Score`], see [`Config::score_weights_ignored`].
+    pub score_server_weight: f64,
+    pub score_cdn_weight: f64,
+    /// Accept invalid certificates of https cdn urls.
+    pub tls_skip_verify: bool,
+    /// Extra headers of cdn requests, e.g. `Host`.
+    pub cdn_request_headers: HashMap<String, String>,
+    /// `GET` or `HEAD`, `cdn_res_body` is not checked for `HEAD`.
+    pub cdn_request_method: String,
+    /// Expected status code range of cdn responses, both ends are inclusive.
+    pub cdn_expected_status_min: u16,
+    pub cdn_expected_status_max: u16,
+    /// Expected status code range of server responses, both ends are inclusive.
+    pub server_expected_status_min: u16,
+    pub server_expected_status_max: u16,
+    /// Match `cdn_res_body` and `server_res_body` as regex, see [`BodyMatcher`].
+    pub cdn_res_body_regex: bool,
+    pub server_res_body_regex: bool,
+    /// User agent of both cdn and server requests.
+    pub user_agent: String,
+    /// Timeout of establishing connections, `max_rtt` is the timeout of the whole request.
+    /// See [`Config::connection_timeout`].
+    pub connection_timeout_ms: Option<u64>,
+    /// Number of sequential rtt samples of each ip, the min is used as rtt.
+    pub rtt_samples: usize,
+    /// Results summary is posted to this url when a test run is done.
+    pub webhook_url: Option<String>,
+    /// Replaces the `dns` section of the sing-box template when set.
+    pub dns_server: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port_base: 31000,
+            max_connection_count: 50,
+            server_url: "http://127.0.0.1/".into(),
+            cdn_url: "".into(),
+            listen_ip: "127.0.0.2".into(),
+            max_rtt: 1000,
+            server_res_body: "".into(),
+            cdn_res_body: "error code: 1003".into(),
+            max_subnet_len: 256,
+            strict_max_failure_pct: 100.0,
+            sort_by: SortKey::ServerRtt,
+            retry_count: 2,
+            retry_delay_ms: 200,
+            parallel_sing_box: 1,
+            sing_box_path: "./sing-box".into(),
+            startup_timeout_secs: 10,
+            sing_box_extra_args: Vec::new(),
+            cdn_urls: Vec::new(),
+            score_server_weight: 0.5,
+            score_cdn_weight: 0.5,
+            tls_skip_verify: false,
+            cdn_request_headers: HashMap::new(),
+            cdn_request_method: "GET".into(),
+            cdn_expected_status_min: 200,
+            cdn_expected_status_max: 299,
+            server_expected_status_min: 200,
+            server_expected_status_max: 299,
+            cdn_res_body_regex: false,
+            server_res_body_regex: false,
+            user_agent: concat!("cdn-ip-tester/", env!("CARGO_PKG_VERSION")).into(),
+            connection_timeout_ms: None,
+            rtt_samples: 1,
+            webhook_url: None,
+            dns_server: None,
+        }
+    }
+}
+
+/// Check a response body by substring, or by regex if `*_res_body_regex` is set.
+#[derive(Clone, Debug)]
+pub enum BodyMatcher {
+    Contains(String),
+    Regex(Regex),
+}
+
+impl BodyMatcher {
+    pub fn new(name: &str, pattern: &str, is_regex: bool) -> Result<Self> {
+        if !is_regex {
+            return Ok(Self::Contains(pattern.into()));
+        }
+        let regex = Regex::new(pattern).map_err(|err| {
+            ErrorKind::config(format!("{name} {pattern:?} is not a valid regex: {err}"))
+        })?;
+        Ok(Self::Regex(regex))
+    }
+
+    pub fn is_match(&self, body: &str) -> bool {
+        match self {
+            BodyMatcher::Contains(expected) => body.contains(expected.as_str()),
+            BodyMatcher::Regex(regex) => regex.is_match(body),
+        }
+    }
+}
+
+impl Display for BodyMatcher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BodyMatcher::Contains(expected) => write!(f, "{expected}"),
+            BodyMatcher::Regex(regex) => write!(f, "regex {}", regex.as_str()),
+        }
+    }
+}
+
+/// Values of these headers are not logged.
+const SENSITIVE_HEADERS: &[&str] = &["authorization", "proxy-authorization", "cookie"];
+
+fn validate_http_url(name: &str, url: &str) -> Result<()> {
+    let parsed_url = Url::parse(url)
+        .map_err(|err| ErrorKind::config(format!("{name} {url:?} is not a valid url: {err}")))?;
+    if parsed_url.scheme() != "http" && parsed_url.scheme() != "https" {
+        Err(ErrorKind::config(format!(
+            "{name} {url:?} scheme must be http or https"
+        )))?
+    }
+    Ok(())
+}
+
+fn apply_env_override<T>(name: &str, value: &mut T) -> Result<()>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Ok(env_value) = env::var(name) {
+        *value = T::from_str(&env_value).map_err(|err| {
+            DeserializedError::custom(
+                format!("Can not parse env {name}={env_value:?}: {err}").as_str(),
+            )
+        })?;
+    }
+    Ok(())
+}
+
+impl Config {
+    pub fn effective_cdn_urls(&self) -> Vec<&str> {
+        if self.cdn_urls.is_empty() {
+            vec![self.cdn_url.as_str()]
+        } else {
+            self.cdn_urls.iter().map(String::as_str).collect()
+        }
+    }
+
+    /// Write [`DEFAULT_CONFIG_TOML`] to `path`, existing file will not be overwritten.
+    pub fn save_default<P: AsRef<Path>>(path: P) -> Result<()> {
+        if path.as_ref().exists() {
+            Err(ErrorKind::config(format!(
+                "{:?} already exists",
+                path.as_ref()
+            )))?
+        }
+        fs::write(&path, DEFAULT_CONFIG_TOML).map_err(|err| ErrorKind::fs(err, &path))?;
+        Ok(())
+    }
+
+    /// Override fields with `CDNTEST_{FIELD_NAME}` environment variables.
+    pub fn apply_env_overrides(&mut self) -> Result<()> {
+        apply_env_override("CDNTEST_PORT_BASE", &mut self.port_base)?;
+        apply_env_override(
+            "CDNTEST_MAX_CONNECTION_COUNT",
+            &mut self.max_connection_count,
+        )?;
+        apply_env_override("CDNTEST_MAX_RTT", &mut self.max_rtt)?;
+        apply_env_override("CDNTEST_SERVER_URL", &mut self.server_url)?;
+        apply_env_override("CDNTEST_CDN_URL", &mut self.cdn_url)?;
+        apply_env_override("CDNTEST_LISTEN_IP", &mut self.listen_ip)?;
+        apply_env_override("CDNTEST_SERVER_RES_BODY", &mut self.server_res_body)?;
+        apply_env_override("CDNTEST_CDN_RES_BODY", &mut self.cdn_res_body)?;
+        apply_env_override("CDNTEST_MAX_SUBNET_LEN", &mut self.max_subnet_len)?;
+        apply_env_override("CDNTEST_SING_BOX_PATH", &mut self.sing_box_path)?;
+        Ok(())
+    }
+
+    pub fn cdn_header_map(&self) -> Result<HeaderMap> {
+        let mut header_map = HeaderMap::new();
+        for (name, value) in &self.cdn_request_headers {
+            let header_name = HeaderName::from_bytes(name.as_bytes()).map_err(|err| {
+                ErrorKind::config(format!("cdn_request_headers {name:?} is not valid: {err}"))
+            })?;
+            let header_value = HeaderValue::from_str(value).map_err(|err| {
+                ErrorKind::config(format!(
+                    "cdn_request_headers {name:?} value {value:?} is not valid: {err}"
+                ))
+            })?;
+            header_map.insert(header_name, header_value);
+        }
+        Ok(header_map)
+    }
+
+    pub fn cdn_body_matcher(&self) -> Result<BodyMatcher> {
+        BodyMatcher::new("cdn_res_body", &self.cdn_res_body, self.cdn_res_body_regex)
+    }
+
+    pub fn server_body_matcher(&self) -> Result<BodyMatcher> {
+        BodyMatcher::new(
+            "server_res_body",
+            &self.server_res_body,
+            self.server_res_body_regex,
+        )
+    }
+
+    /// `connection_timeout_ms`, or `max_rtt` if it is not set.
+    pub fn connection_timeout(&self) -> Duration {
+        Duration::from_millis(self.connection_timeout_ms.unwrap_or(self.max_rtt))
+    }
+
+    pub fn cdn_expected_status(&self) -> RangeInclusive<u16> {
+        self.cdn_expected_status_min..=self.cdn_expected_status_max
+    }
+
+    pub fn server_expected_status(&self) -> RangeInclusive<u16> {
+        self.server_expected_status_min..=self.server_expected_status_max
+    }
+
+    pub fn cdn_method(&self) -> Method {
+        if self.cdn_request_method == "HEAD" {
+            Method::HEAD
+        } else {
+            Method::GET
+        }
+    }
+
+    /// `cdn_request_headers` for logging, values of sensitive headers are redacted.
+    pub fn redacted_cdn_request_headers(&self) -> Vec<(&str, &str)> {
+        let mut headers: Vec<(&str, &str)> = self
+            .cdn_request_headers
+            .iter()
+            .map(|(name, value)| {
+                if SENSITIVE_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+                    (name.as_str(), "<redacted>")
+                } else {
+                    (name.as_str(), value.as_str())
+                }
+            })
+            .collect();
+        headers.sort_unstable();
+        headers
+    }
+
+    pub fn score_weights(&self) -> ScoreWeights {
+        ScoreWeights {
+            server_weight: self.score_server_weight,
+            cdn_weight: self.score_cdn_weight,
+        }
+    }
+
+    /// Non-default score weights have no effect unless the results are sorted by
+    /// [`SortKey::Score`].
+    pub fn score_weights_ignored(&self, sort_key: SortKey) -> bool {
+        sort_key != SortKey::Score && self.score_weights() != ScoreWeights::default()
+    }
+
+    /// Why a batch of `tested` ips with `success` successes fails `--strict-mode`: any body
+    /// unmatched warning, or a failure rate above `strict_max_failure_pct`.
+    pub fn strict_violation(
+        &self,
+        body_warning_count: usize,
+        tested: usize,
+        success: usize,
+    ) -> Option<String> {
+        if body_warning_count != 0 {
+            return Some(format!(
+                "{body_warning_count} body unmatched warnings in batch"
+            ));
+        }
+        if tested != 0 {
+            let failure_pct = (tested - success) as f64 * 100.0 / tested as f64;
+            if failure_pct > self.strict_max_failure_pct {
+                return Some(format!(
+                    "batch failure rate {failure_pct:.2}% exceeds strict_max_failure_pct {}%",
+                    self.strict_max_failure_pct
+                ));
+            }
+        }
+        None
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        validate_http_url("server_url", &self.server_url)?;
+        if !self.cdn_url.is_empty() {
+            validate_http_url("cdn_url", &self.cdn_url)?;
+        }
+        for cdn_url in &self.cdn_urls {
+            validate_http_url("cdn_urls", cdn_url)?;
+        }
+        if let Some(webhook_url) = &self.webhook_url {
+            validate_http_url("webhook_url", webhook_url)?;
+        }
+        self.cdn_header_map()?;
+        self.cdn_body_matcher()?;
+        self.server_body_matcher()?;
+        if self.cdn_request_method != "GET" && self.cdn_request_method != "HEAD" {
+            Err(ErrorKind::config(format!(
+                "cdn_request_method {:?} must be GET or HEAD",
+                self.cdn_request_method
+            )))?
+        }
+        if self.max_connection_count == 0 {
+            Err(ErrorKind::config(
+                "max_connection_count must be greater than 0".into(),
+            ))?
+        }
+        if u16::try_from(self.max_connection_count)
+            .ok()
+            .and_then(|max_connection_count| self.port_base.checked_add(max_connection_count))
+            .is_none()
+        {
+            Err(ErrorKind::config(format!(
+                "port_base {} + max_connection_count {} overflows u16",
+                self.port_base, self.max_connection_count
+            )))?
+        }
+        if self.parallel_sing_box == 0 {
+            Err(ErrorKind::config(
+                "parallel_sing_box must be greater than 0".into(),
+            ))?
+        }
+        if self.max_rtt == 0 {
+            Err(ErrorKind::config("max_rtt must be greater than 0".into()))?
+        }
+        if self.rtt_samples == 0 {
+            Err(ErrorKind::config(
+                "rtt_samples must be greater than 0".into(),
+            ))?
+        }
+        if self.retry_count > MAX_RETRY_COUNT {
+            Err(ErrorKind::config(format!(
+                "retry_count {} must not be greater than {MAX_RETRY_COUNT}",
+                self.retry_count
+            )))?
+        }
+        if self.connection_timeout_ms == Some(0) {
+            Err(ErrorKind::config(
+                "connection_timeout_ms must be greater than 0".into(),
+            ))?
+        }
+        for (name, weight) in [
+            ("score_server_weight", self.score_server_weight),
+            ("score_cdn_weight", self.score_cdn_weight),
+        ] {
+            if !weight.is_finite() || weight < 0.0 {
+                Err(ErrorKind::config(format!(
+                    "{name} {weight} must be a non-negative number"
+                )))?
+            }
+        }
+        for (name, expected_status) in [
+            ("cdn_expected_status", self.cdn_expected_status()),
+            ("server_expected_status", self.server_expected_status()),
+        ] {
+            if expected_status.is_empty() {
+                Err(ErrorKind::config(format!(
+                    "{name}_min {} must not be greater than {name}_max {}",
+                    expected_status.start(),
+                    expected_status.end()
+                )))?
+            }
+        }
+        if let Err(err) = IpAddr::from_str(&self.listen_ip) {
+            Err(ErrorKind::config(format!(
+                "listen_ip {:?} is not a valid ip: {err}",
+                self.listen_ip
+            )))?
+        }
+        Ok(())
+    }
 }
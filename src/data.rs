@@ -1,15 +1,18 @@
 use std::collections::HashSet;
 use std::fs;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::path::Path;
 use std::str::FromStr;
 
 use cidr::errors::NetworkParseError;
 use cidr::{IpCidr, IpInet, Ipv4Inet, Ipv6Inet};
 use lazy_static::lazy_static;
-use log::warn;
+use log::{info, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use regex::Regex;
 
+use crate::cache::RttResults;
 use crate::error;
 
 pub trait Loadable<T> {
@@ -22,52 +25,408 @@ pub trait Loadable<T> {
                 .as_str(),
         )
     }
+
+    /// Same as [`Loadable::load`] but does not block the executor.
+    #[allow(async_fn_in_trait)]
+    async fn load_async<P: AsRef<Path>>(path: P) -> error::Result<T> {
+        Self::from_str(
+            tokio::fs::read_to_string(&path)
+                .await
+                .map_err(|err| error::ErrorKind::fs(err, &path))?
+                .as_str(),
+        )
+    }
+
+    /// Same as [`Loadable::load`] but returns the default value if the file can not be read,
+    /// parse errors are still returned.
+    fn load_or_default<P: AsRef<Path>>(path: P) -> error::Result<T>
+    where
+        T: Default,
+    {
+        default_if_unreadable(Self::load(&path), path)
+    }
+
+    /// Same as [`Loadable::load_or_default`] but does not block the executor.
+    #[allow(async_fn_in_trait)]
+    async fn load_or_default_async<P: AsRef<Path>>(path: P) -> error::Result<T>
+    where
+        T: Default,
+    {
+        default_if_unreadable(Self::load_async(&path).await, path)
+    }
+}
+
+/// Replace an [`error::ErrorKind::Fs`] error of loading `path` with the default value.
+pub fn default_if_unreadable<T: Default, P: AsRef<Path>>(
+    res: error::Result<T>,
+    path: P,
+) -> error::Result<T> {
+    match res {
+        Err(err) if matches!(*err.0, error::ErrorKind::Fs { .. }) => {
+            info!("Can not load {:?}, use default: {err}", path.as_ref());
+            Ok(T::default())
+        }
+        res => res,
+    }
 }
 
 pub trait Savable {
     fn to_string(&self) -> error::Result<String>;
+
+    /// The bytes written by [`Savable::save`], binary formats override this.
+    fn to_bytes(&self) -> error::Result<Vec<u8>> {
+        Ok(self.to_string()?.into_bytes())
+    }
+
     fn save<P: AsRef<Path>>(&self, path: P) -> error::Result<()> {
-        fs::write(&path, self.to_string()?).map_err(|err| error::ErrorKind::fs(err, &path).into())
+        write_atomic(path, &self.to_bytes()?)
+    }
+
+    /// Copy the existing file to `path.with_extension("bak")` before saving.
+    fn save_backup<P: AsRef<Path>>(&self, path: P) -> error::Result<()> {
+        if path.as_ref().exists() {
+            let backup_path = path.as_ref().with_extension("bak");
+            fs::copy(&path, &backup_path).map_err(|err| error::ErrorKind::fs(err, &backup_path))?;
+        }
+        self.save(path)
+    }
+
+    /// Same as [`Savable::save`] but does not block the executor.
+    #[allow(async_fn_in_trait)]
+    async fn save_async<P: AsRef<Path>>(&self, path: P) -> error::Result<()> {
+        write_atomic_async(path, &self.to_bytes()?).await
+    }
+
+    /// Same as [`Savable::save_backup`] but does not block the executor.
+    #[allow(async_fn_in_trait)]
+    async fn save_backup_async<P: AsRef<Path>>(&self, path: P) -> error::Result<()> {
+        if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            let backup_path = path.as_ref().with_extension("bak");
+            tokio::fs::copy(&path, &backup_path)
+                .await
+                .map_err(|err| error::ErrorKind::fs(err, &backup_path))?;
+        }
+        self.save_async(path).await
+    }
+}
+
+/// Write to a temp file then rename it to `path`, so an interrupted write never leaves
+/// `path` half-written.
+pub fn write_atomic<P: AsRef<Path>>(path: P, contents: &[u8]) -> error::Result<()> {
+    let tmp_path = path.as_ref().with_extension("tmp");
+    fs::write(&tmp_path, contents).map_err(|err| error::ErrorKind::fs(err, &tmp_path))?;
+    if let Err(err) = fs::rename(&tmp_path, &path) {
+        warn!(
+            "rename {tmp_path:?} to {:?} failed: {err:?} , write directly.",
+            path.as_ref()
+        );
+        let _ = fs::remove_file(&tmp_path);
+        fs::write(&path, contents).map_err(|err| error::ErrorKind::fs(err, &path))?;
+    }
+    Ok(())
+}
+
+/// Same as [`write_atomic`] but does not block the executor.
+pub async fn write_atomic_async<P: AsRef<Path>>(path: P, contents: &[u8]) -> error::Result<()> {
+    let tmp_path = path.as_ref().with_extension("tmp");
+    tokio::fs::write(&tmp_path, contents)
+        .await
+        .map_err(|err| error::ErrorKind::fs(err, &tmp_path))?;
+    if let Err(err) = tokio::fs::rename(&tmp_path, &path).await {
+        warn!(
+            "rename {tmp_path:?} to {:?} failed: {err:?} , write directly.",
+            path.as_ref()
+        );
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+        tokio::fs::write(&path, contents)
+            .await
+            .map_err(|err| error::ErrorKind::fs(err, &path))?;
     }
+    Ok(())
+}
+
+/// Rng of the random ip picked for `ip_idx` of the `subnet_idx`th subnet. It only depends on
+/// its arguments, so a resumed run picks the same ips as an uninterrupted run with the same seed.
+pub fn random_ip_rng(seed: u64, subnet_idx: usize, ip_idx: usize) -> StdRng {
+    let mut key = [0; 32];
+    key[..8].copy_from_slice(&seed.to_le_bytes());
+    key[8..16].copy_from_slice(&(subnet_idx as u64).to_le_bytes());
+    key[16..24].copy_from_slice(&(ip_idx as u64).to_le_bytes());
+    StdRng::from_seed(key)
 }
 
-// TODO add ipv6 support
-impl Loadable<Self> for HashSet<Subnet> {
+/// Lowercase hex of `bytes`, used as the text form of binary data.
+pub fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Inverse of [`hex_encode`].
+pub fn hex_decode(s: &str) -> error::Result<Vec<u8>> {
+    let s = s.trim();
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        Err(error::DeserializedError::custom("invalid hex string"))?
+    }
+    let mut bytes = Vec::with_capacity(s.len() / 2);
+    for i in (0..s.len()).step_by(2) {
+        bytes.push(u8::from_str_radix(&s[i..i + 2], 16).map_err(error::DeserializedError::from)?);
+    }
+    Ok(bytes)
+}
+
+impl Loadable<Self> for Vec<Subnet> {
     fn from_str(s: &str) -> error::Result<Self> {
-        lazy_static! {
-            static ref RE_V4_SUBNET_MATCH: Regex =
-                Regex::new(r"(\d{1,3}\.\d{1,3}\.\d{1,3}\.\d{1,3}/\d{1,3})").unwrap();
-            static ref RE_V4_MATCH: Regex =
-                Regex::new(r"(\d{1,3}\.\d{1,3}\.\d{1,3}\.\d{1,3})").unwrap();
-        }
-        let mut ret = HashSet::new();
-
-        for cap in RE_V4_SUBNET_MATCH.captures_iter(s) {
-            match Subnet::from_str(&cap[0]) {
-                Ok(subnet) => {
-                    ret.insert(subnet);
+        parse_subnets(s, false)
+    }
+}
+
+/// Load subnets from `path`, in strict mode any subnet parse failure is an error.
+pub fn load_subnets<P: AsRef<Path>>(path: P, strict: bool) -> error::Result<Vec<Subnet>> {
+    parse_subnets(
+        fs::read_to_string(&path)
+            .map_err(|err| error::ErrorKind::fs(err, &path))?
+            .as_str(),
+        strict,
+    )
+}
+
+/// Parse `s` as a list of one ip or subnet per line, bare ips are treated as single host subnets.
+/// Return `None` if any non-empty line is neither an ip nor a subnet.
+fn parse_ip_list(s: &str) -> Option<Vec<Subnet>> {
+    s.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            IpAddr::from_str(line)
+                .map(Subnet::new_host)
+                .or_else(|_| Subnet::from_str(line))
+                .ok()
+        })
+        .collect()
+}
+
+/// Subnets keep the order of the input, duplicated subnets are skipped.
+///
+/// A plain list of ips and subnets, one per line, is parsed line by line and also supports bare
+/// ipv6 ips, otherwise subnets are searched in the text.
+pub fn parse_subnets(s: &str, strict: bool) -> error::Result<Vec<Subnet>> {
+    if let Some(subnets) = parse_ip_list(s) {
+        return Ok(remove_duplicated_subnets(subnets));
+    }
+
+    lazy_static! {
+        static ref RE_V4_SUBNET_MATCH: Regex =
+            Regex::new(r"(\d{1,3}\.\d{1,3}\.\d{1,3}\.\d{1,3}/\d{1,3})").unwrap();
+        static ref RE_V6_SUBNET_MATCH: Regex =
+            Regex::new(r"([0-9a-fA-F]{0,4}(?::[0-9a-fA-F]{0,4}){2,7}/\d{1,3})").unwrap();
+        static ref RE_V4_MATCH: Regex =
+            Regex::new(r"\b(\d{1,3}\.\d{1,3}\.\d{1,3}\.\d{1,3})\b").unwrap();
+        static ref RE_V4_RANGE_MATCH: Regex = Regex::new(
+            r"\b(\d{1,3}\.\d{1,3}\.\d{1,3}\.\d{1,3})\s*-\s*(\d{1,3}\.\d{1,3}\.\d{1,3}\.\d{1,3})\b"
+        )
+        .unwrap();
+    }
+    // (offset in `s`, subnet), sorted by offset as each kind of subnet is searched separately
+    let mut ret = Vec::new();
+    let mut subnet_ranges = Vec::new();
+
+    for cap in RE_V4_SUBNET_MATCH
+        .captures_iter(s)
+        .chain(RE_V6_SUBNET_MATCH.captures_iter(s))
+    {
+        let range = cap.get(0).unwrap().range();
+        subnet_ranges.push(range.clone());
+        match Subnet::from_str(&cap[0]) {
+            Ok(subnet) => ret.push((range.start, subnet)),
+            Err(err) => {
+                if strict {
+                    return Err(error::DeserializedError::from(err))?;
+                }
+                warn!("parse {:?} to subnet failed: {err:?} , skip.", &cap[0]);
+            }
+        }
+    }
+
+    // ranges like `1.2.3.0-1.2.3.255` are converted to subnets
+    for cap in RE_V4_RANGE_MATCH.captures_iter(s) {
+        let range = cap.get(0).unwrap().range();
+        subnet_ranges.push(range.clone());
+        match (Ipv4Addr::from_str(&cap[1]), Ipv4Addr::from_str(&cap[2])) {
+            (Ok(start), Ok(end)) => ret.extend(
+                range_to_cidrs(start, end)
+                    .into_iter()
+                    .map(|cidr| (range.start, Subnet::from(cidr))),
+            ),
+            (Err(err), _) | (_, Err(err)) => {
+                if strict {
+                    return Err(error::DeserializedError::from(err))?;
+                }
+                warn!("parse {:?} to subnet failed: {err:?} , skip.", &cap[0]);
+            }
+        }
+    }
+
+    // bare ip is treated as a single host subnet, skip the ones which are part of a subnet
+    for cap in RE_V4_MATCH.captures_iter(s) {
+        let range = cap.get(0).unwrap().range();
+        if subnet_ranges
+            .iter()
+            .any(|subnet_range| range.start < subnet_range.end && subnet_range.start < range.end)
+        {
+            continue;
+        }
+        match Ipv4Addr::from_str(&cap[0]) {
+            Ok(ip_addr) => ret.push((range.start, Subnet::new_host(IpAddr::V4(ip_addr)))),
+            Err(err) => {
+                if strict {
+                    return Err(error::DeserializedError::from(err))?;
                 }
-                Err(err) => {
-                    warn!("parse {:?} to subnet failed: {err:?} , skip.", &cap[0]);
+                warn!("parse {:?} to subnet failed: {err:?} , skip.", &cap[0]);
+            }
+        }
+    }
+    // stable, the cidrs of a range keep their order
+    ret.sort_by_key(|(offset, _)| *offset);
+    Ok(remove_duplicated_subnets(
+        ret.into_iter().map(|(_, subnet)| subnet).collect(),
+    ))
+}
+
+/// Keep the first one of the subnets with the same cidr.
+fn remove_duplicated_subnets(mut subnets: Vec<Subnet>) -> Vec<Subnet> {
+    let mut subnet_set = HashSet::new();
+    subnets.retain(|subnet| subnet_set.insert(subnet.cidr));
+    subnets
+}
+
+/// The minimal list of cidrs which exactly cover `start..=end`.
+pub fn range_to_cidrs(start: Ipv4Addr, end: Ipv4Addr) -> Vec<IpCidr> {
+    if start > end {
+        warn!("ip range {start}-{end} is empty as {start} > {end}");
+        return Vec::new();
+    }
+    let mut ret = Vec::new();
+    // u64 so that the end of 255.255.255.255 does not overflow
+    let (mut start, end) = (u64::from(u32::from(start)), u64::from(u32::from(end)));
+    while start <= end {
+        // the largest aligned block starting at `start` which does not exceed `end`
+        let mut host_bits = start.trailing_zeros().min(32);
+        while start + (1 << host_bits) - 1 > end {
+            host_bits -= 1;
+        }
+        ret.push(IpCidr::new(Ipv4Addr::from(start as u32).into(), (32 - host_bits) as u8).unwrap());
+        start += 1 << host_bits;
+    }
+    ret
+}
+
+/// Warn about overlapping subnets, the subnets covered by another one are removed when `dedup`
+/// is set, the first one of exact duplicates is kept. Return the count of overlapping subnet pairs.
+pub fn check_overlapping_subnets(subnets: &mut Vec<Subnet>, dedup: bool) -> usize {
+    fn sort_key(subnet: &Subnet) -> (bool, u128, u8) {
+        match subnet.cidr.first_address() {
+            IpAddr::V4(addr) => (
+                false,
+                u128::from(u32::from(addr)),
+                subnet.cidr.network_length(),
+            ),
+            IpAddr::V6(addr) => (true, u128::from(addr), subnet.cidr.network_length()),
+        }
+    }
+
+    // stable, the first one of exact duplicates becomes the outer subnet
+    let mut sorted_idxs: Vec<usize> = (0..subnets.len()).collect();
+    sorted_idxs.sort_by_key(|idx| sort_key(&subnets[*idx]));
+
+    // cidrs are either nested or disjoint, so comparing with the outermost previous subnet is enough
+    let mut overlap_count = 0;
+    let mut covered_idxs = HashSet::new();
+    let mut outer_subnet: Option<&Subnet> = None;
+    for idx in sorted_idxs {
+        let subnet = &subnets[idx];
+        match outer_subnet {
+            Some(outer) if outer.overlaps(subnet) => {
+                overlap_count += 1;
+                covered_idxs.insert(idx);
+                if outer.cidr == subnet.cidr {
+                    warn!("subnet {} is duplicated", subnet.cidr);
+                } else {
+                    warn!("subnet {} overlaps with subnet {}", subnet.cidr, outer.cidr);
                 }
             }
+            _ => outer_subnet = Some(subnet),
         }
+    }
 
-        // TODO: 修好它
-        // for cap in RE_V4_MATCH.captures_iter(s) {
-        //     match IpAddr::from_str(&cap[0]) {
-        //         Ok(ip_addr) => {
-        //             ret.push(Subnet::new(ip_addr, Family::Ipv4.len()).unwrap());
-        //         }
-        //         Err(err) => {
-        //             warn!("parse {:?} to subnet failed: {err:?} , skip.", &cap[0]);
-        //         }
-        //     }
-        // }
-        Ok(ret)
+    if dedup {
+        let mut idx = 0;
+        subnets.retain(|_| {
+            idx += 1;
+            !covered_idxs.contains(&(idx - 1))
+        });
     }
+    overlap_count
+}
+
+/// IANA special-purpose prefixes, ips in them are never reachable through a cdn.
+pub const BOGON_SUBNETS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/127",
+    "::ffff:0:0/96",
+    "64:ff9b::/96",
+    "100::/64",
+    "2001:db8::/32",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+fn is_covered(subnet: &Subnet, excluded_subnets: &[Subnet]) -> bool {
+    excluded_subnets.iter().any(|excluded_subnet| {
+        excluded_subnet.cidr.network_length() <= subnet.cidr.network_length()
+            && excluded_subnet.contains(subnet.cidr.first_address())
+    })
+}
+
+/// Remove the subnets which are fully covered by `excluded_subnets`, return the removed count.
+pub fn exclude_subnets(subnets: &mut Vec<Subnet>, excluded_subnets: &[Subnet]) -> usize {
+    let len = subnets.len();
+    subnets.retain(|subnet| !is_covered(subnet, excluded_subnets));
+    len - subnets.len()
+}
+
+/// Remove the subnets which are fully covered by [`BOGON_SUBNETS`] and return the bogon subnets,
+/// ips of the subnets which straddle a bogon prefix should be checked against them one by one.
+pub fn exclude_bogon_subnets(subnets: &mut Vec<Subnet>) -> Vec<Subnet> {
+    let bogon_subnets: Vec<Subnet> = BOGON_SUBNETS
+        .iter()
+        .map(|bogon_subnet| Subnet::from_str(bogon_subnet).unwrap())
+        .collect();
+    subnets.retain(|subnet| {
+        let is_bogon = is_covered(subnet, &bogon_subnets);
+        if is_bogon {
+            info!("Skip bogon subnet {}", subnet.cidr);
+        }
+        !is_bogon
+    });
+    bogon_subnets
 }
 
+/// Max number of subnets one subnet is split into by [`Subnet::split`], e.g. a `/8` into `/28`.
+pub const MAX_SPLIT_CHUNK_COUNT: u128 = 1 << 20;
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct Subnet {
     pub cidr: IpCidr,
@@ -78,16 +437,122 @@ impl FromStr for Subnet {
     type Err = NetworkParseError;
     fn from_str(s: &str) -> Result<Self, NetworkParseError> {
         let ip_inet = IpInet::from_str(s)?;
-        Ok(Self {
-            cidr: IpCidr::new(ip_inet.first_address(), ip_inet.network_length())?,
+        Ok(Self::from(IpCidr::new(
+            ip_inet.first_address(),
+            ip_inet.network_length(),
+        )?))
+    }
+}
+
+impl From<IpCidr> for Subnet {
+    fn from(cidr: IpCidr) -> Self {
+        Self {
+            cidr,
             enable: false,
-        })
+        }
     }
 }
 
 impl Subnet {
+    pub fn new_host(addr: IpAddr) -> Self {
+        Self::from(IpCidr::new_host(addr))
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        self.cidr.contains(&addr)
+    }
+
+    pub fn overlaps(&self, other: &Subnet) -> bool {
+        self.contains(other.cidr.first_address()) || other.contains(self.cidr.first_address())
+    }
+
     pub fn len(&self) -> usize {
-        1 << (self.cidr.family().len() - self.cidr.network_length())
+        // ipv6 subnet may have more than usize::MAX ips
+        1_usize
+            .checked_shl(u32::from(
+                self.cidr.family().len() - self.cidr.network_length(),
+            ))
+            .unwrap_or(usize::MAX)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        // a cidr always contains at least one ip
+        false
+    }
+
+    /// Map the `idx`th tested ip to an ip index which visits `0, stride, 2 * stride, ...` first,
+    /// then `1, 1 + stride, ...`, so every index of the subnet is visited exactly once.
+    pub fn strided_index(&self, idx: usize, stride: usize) -> usize {
+        let len = self.len();
+        if stride <= 1 || idx >= len {
+            return idx;
+        }
+        // the first `len % stride` residues have one more index than the others
+        let (quotient, remainder) = (len / stride, len % stride);
+        let (residue, row) = if idx < remainder * (quotient + 1) {
+            (idx / (quotient + 1), idx % (quotient + 1))
+        } else {
+            let idx = idx - remainder * (quotient + 1);
+            (remainder + idx / quotient, idx % quotient)
+        };
+        residue + row * stride
+    }
+
+    /// Split into `2^(chunk_prefix_len - network_length)` subnets of `chunk_prefix_len`, which
+    /// inherit `enable`. A subnet which is not larger than `chunk_prefix_len` is not split, more
+    /// than [`MAX_SPLIT_CHUNK_COUNT`] chunks is an error.
+    pub fn split(&self, chunk_prefix_len: u8) -> error::Result<Vec<Subnet>> {
+        let family_len = self.cidr.family().len();
+        let network_length = self.cidr.network_length();
+        let chunk_prefix_len = chunk_prefix_len.min(family_len);
+        if chunk_prefix_len <= network_length {
+            return Ok(vec![self.clone()]);
+        }
+        let chunk_count = 1_u128
+            .checked_shl(u32::from(chunk_prefix_len - network_length))
+            .unwrap_or(u128::MAX);
+        if chunk_count > MAX_SPLIT_CHUNK_COUNT {
+            Err(error::ErrorKind::config(format!(
+                "splitting {} into /{chunk_prefix_len} subnets makes more than {MAX_SPLIT_CHUNK_COUNT} subnets",
+                self.cidr
+            )))?
+        }
+        let chunk_size = 1_u128 << (family_len - chunk_prefix_len);
+        let first_address = match self.cidr.first_address() {
+            IpAddr::V4(addr) => u128::from(u32::from(addr)),
+            IpAddr::V6(addr) => u128::from(addr),
+        };
+        Ok((0..chunk_count)
+            .map(|idx| {
+                let addr = first_address + idx * chunk_size;
+                let addr = match self.cidr {
+                    IpCidr::V4(_) => IpAddr::V4(Ipv4Addr::from(addr as u32)),
+                    IpCidr::V6(_) => IpAddr::V6(Ipv6Addr::from(addr)),
+                };
+                Subnet {
+                    cidr: IpCidr::new(addr, chunk_prefix_len).unwrap(),
+                    enable: self.enable,
+                }
+            })
+            .collect())
+    }
+
+    /// Lazily yield every ip of the subnet, ipv6 subnets are capped at `usize::MAX` ips.
+    pub fn iter(&self) -> impl Iterator<Item = IpInet> + '_ {
+        (0..self.len()).map_while(|idx| self.get_ip(idx))
+    }
+
+    /// The best `server_rtt` of the ips in the subnet, `None` if no ip of the subnet has a result.
+    pub fn score(&self, results: &RttResults) -> Option<u64> {
+        results
+            .iter()
+            .filter(|(ip_inet, _)| self.contains(ip_inet.address()))
+            .map(|(_, rtt_result)| rtt_result.server_rtt())
+            .min()
+    }
+
+    pub fn random_ip(&self, rng: &mut impl Rng) -> IpInet {
+        self.get_ip(rng.gen_range(0..self.len())).unwrap()
     }
 
     pub fn get_ip(&self, idx: usize) -> Option<IpInet> {
@@ -11,6 +11,16 @@ pub type Result<T> = core::result::Result<T, Error>;
 pub enum SerializedError {
     Toml(#[from] toml::ser::Error),
     Json(#[from] serde_json::error::Error),
+    Yaml(#[from] serde_yaml::Error),
+    Bincode(#[from] bincode::Error),
+    #[error("{0}")]
+    Custom(String),
+}
+
+impl SerializedError {
+    pub fn custom(reason: &str) -> Self {
+        Self::Custom(reason.into())
+    }
 }
 
 #[derive(ThisError, Debug)]
@@ -18,6 +28,8 @@ pub enum SerializedError {
 pub enum DeserializedError {
     Toml(#[from] toml::de::Error),
     Json(#[from] serde_json::error::Error),
+    Yaml(#[from] serde_yaml::Error),
+    Bincode(#[from] bincode::Error),
     #[error("{unmatched:?} unmatched regex: \"{regex}\"")]
     Regex {
         unmatched: String,
@@ -51,6 +63,8 @@ pub enum ReqwestError {
     Network { source: reqwest::Error },
     #[error("Expected body {expected:?} not found in {body:?}")]
     BodyNoMatch { body: String, expected: String },
+    #[error("Unexpected status {status} from {url}")]
+    StatusError { status: u16, url: String },
 }
 
 impl ReqwestError {
@@ -63,6 +77,9 @@ impl ReqwestError {
     pub fn body_no_match(body: String, expected: String) -> Self {
         Self::BodyNoMatch { body, expected }
     }
+    pub fn status_error(status: u16, url: String) -> Self {
+        Self::StatusError { status, url }
+    }
 }
 
 #[derive(ThisError, Debug)]
@@ -105,6 +122,21 @@ pub enum ErrorKind {
     Reqwest(#[from] ReqwestError, Backtrace),
     #[error("JoinError error\nCause: {0}\nBacktrace: {1}")]
     Tokio(#[from] TokioError, Backtrace),
+    #[error("Config error: {message}\nBacktrace: {backtrace}")]
+    Config {
+        message: String,
+        backtrace: Backtrace,
+    },
+    #[error("Strict mode violation: {message}\nBacktrace: {backtrace}")]
+    Strict {
+        message: String,
+        backtrace: Backtrace,
+    },
+    #[error("Aborted: {message}\nBacktrace: {backtrace}")]
+    Aborted {
+        message: String,
+        backtrace: Backtrace,
+    },
 }
 
 impl ErrorKind {
@@ -121,4 +153,22 @@ impl ErrorKind {
             backtrace: Backtrace::capture(),
         }
     }
+    pub fn config(message: String) -> Self {
+        Self::Config {
+            message,
+            backtrace: Backtrace::capture(),
+        }
+    }
+    pub fn strict(message: String) -> Self {
+        Self::Strict {
+            message,
+            backtrace: Backtrace::capture(),
+        }
+    }
+    pub fn aborted(message: String) -> Self {
+        Self::Aborted {
+            message,
+            backtrace: Backtrace::capture(),
+        }
+    }
 }
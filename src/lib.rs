@@ -1,4 +1,15 @@
 #![feature(error_generic_member_access)]
 
+// the derive macros refer to `::cdn_ip_tester`, which also works inside this crate
+extern crate self as cdn_ip_tester;
+
+pub mod cache;
+pub mod config;
 pub mod data;
 pub mod error;
+pub mod logger;
+pub mod metrics;
+pub mod progress;
+pub mod rtt;
+pub mod sing_box;
+pub mod template;
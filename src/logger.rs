@@ -0,0 +1,164 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+use clap::ValueEnum;
+use env_logger::WriteStyle;
+use log::kv::{Key, Value, VisitSource};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value as JsonValue};
+
+use crate::cache::unix_timestamp;
+use crate::error::{ErrorKind, Result};
+
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    /// One json object per line, see [`json_log_line`]
+    Json,
+}
+
+/// Collect the key-values of a log record, e.g. `info!(subnet_count = 42; "...")`.
+struct JsonFieldVisitor<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFieldVisitor<'_> {
+    fn visit_pair(
+        &mut self,
+        key: Key<'kvs>,
+        value: Value<'kvs>,
+    ) -> std::result::Result<(), log::kv::Error> {
+        let value = if let Some(value) = value.to_u64() {
+            value.into()
+        } else if let Some(value) = value.to_i64() {
+            value.into()
+        } else if let Some(value) = value.to_bool() {
+            value.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+pub fn record_fields(record: &Record) -> Map<String, JsonValue> {
+    let mut fields = Map::new();
+    // the visitor never fails
+    let _ = record
+        .key_values()
+        .visit(&mut JsonFieldVisitor(&mut fields));
+    fields
+}
+
+/// Format a log line like `{"level":"INFO","msg":"...","ts":1234567890}`,
+/// fields can not override `level`, `ts` and `msg`.
+pub fn json_log_line(level: Level, ts: u64, msg: &str, fields: Map<String, JsonValue>) -> String {
+    let mut line = Map::new();
+    line.insert("level".into(), level.as_str().into());
+    line.insert("ts".into(), ts.into());
+    line.insert("msg".into(), msg.into());
+    for (key, value) in fields {
+        line.entry(key).or_insert(value);
+    }
+    JsonValue::Object(line).to_string()
+}
+
+/// `NO_COLOR` disables colors when it is set and not empty, see <https://no-color.org>.
+pub fn no_color_env() -> bool {
+    std::env::var_os("NO_COLOR").is_some_and(|no_color| !no_color.is_empty())
+}
+
+/// Plain log line of the log file, like `1234567890 INFO  cdn_ip_tester: ...`.
+pub fn file_log_line(level: Level, ts: u64, target: &str, msg: &str) -> String {
+    format!("{ts} {level:<5} {target}: {msg}")
+}
+
+fn format_record(log_format: LogFormat, record: &Record) -> String {
+    match log_format {
+        LogFormat::Pretty => file_log_line(
+            record.level(),
+            unix_timestamp(),
+            record.target(),
+            &record.args().to_string(),
+        ),
+        LogFormat::Json => json_log_line(
+            record.level(),
+            unix_timestamp(),
+            &record.args().to_string(),
+            record_fields(record),
+        ),
+    }
+}
+
+/// Log to stderr as before, and also append to a log file with its own level.
+struct DualLogger {
+    stderr_logger: Box<dyn Log>,
+    log_file: Mutex<File>,
+    log_file_level: LevelFilter,
+    log_format: LogFormat,
+}
+
+impl Log for DualLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.stderr_logger.enabled(metadata) || metadata.level() <= self.log_file_level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.stderr_logger.enabled(record.metadata()) {
+            self.stderr_logger.log(record);
+        }
+        if record.level() <= self.log_file_level {
+            let line = format_record(self.log_format, record);
+            if let Ok(mut log_file) = self.log_file.lock() {
+                // there is nowhere to report a failed log write
+                let _ = writeln!(log_file, "{line}");
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr_logger.flush();
+        if let Ok(mut log_file) = self.log_file.lock() {
+            let _ = log_file.flush();
+        }
+    }
+}
+
+/// `log_file` is appended with `log_file_level`, stderr always uses `level`.
+pub fn init_logger(
+    log_format: LogFormat,
+    level: LevelFilter,
+    log_file: Option<(&str, LevelFilter)>,
+    no_color: bool,
+) -> Result<()> {
+    let mut builder = pretty_env_logger::formatted_builder();
+    builder.filter_level(level);
+    if no_color {
+        builder.write_style(WriteStyle::Never);
+    }
+    if log_format == LogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", format_record(LogFormat::Json, record)));
+    }
+    let Some((log_file_path, log_file_level)) = log_file else {
+        builder.init();
+        return Ok(());
+    };
+
+    let log_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(Path::new(log_file_path))
+        .map_err(|err| ErrorKind::fs(err, log_file_path))?;
+    let logger = DualLogger {
+        stderr_logger: Box::new(builder.build()),
+        log_file: Mutex::new(log_file),
+        log_file_level,
+        log_format,
+    };
+    // the logger is only initialized once at startup
+    log::set_boxed_logger(Box::new(logger)).unwrap();
+    log::set_max_level(level.max(log_file_level));
+    Ok(())
+}
@@ -1,94 +1,166 @@
 #![feature(error_generic_member_access)]
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
-use std::net::{IpAddr, SocketAddr};
-use std::process::Stdio;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant};
 
 use cidr::IpInet;
-use clap::Parser;
-use indicatif::{ProgressBar, ProgressStyle};
-use log::{debug, error, info, warn, LevelFilter};
-use reqwest::{Client, Url};
-use tokio::io::AsyncReadExt;
-use tokio::process::{Child, Command};
-use tokio::runtime::Handle;
-
-use crate::cache::{RttResult, RttResultCache, RttResults};
-use crate::config::Config;
-use crate::data::{Loadable, Savable, Subnet};
-use crate::error::{DeserializedError, ErrorKind, ReqwestError, Result, TokioError};
-use crate::template::{Outbound, SingBoxConfig};
-
-mod cache;
-mod config;
-mod data;
-mod error;
-mod template;
+use clap::builder::RangedU64ValueParser;
+use clap::{ArgAction, Parser, Subcommand};
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use indicatif::{ProgressState, ProgressStyle};
+use log::{debug, error, info, log_enabled, warn, Level, LevelFilter};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use reqwest::header::HeaderMap;
+use reqwest::{Client, Method, Url};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+use cdn_ip_tester::cache::{
+    subnet_file_hash, unix_timestamp, OutputFormat, RttResult, RttResultCache, RttResults, SortKey,
+};
+use cdn_ip_tester::config::{BodyMatcher, Config};
+use cdn_ip_tester::data::{
+    check_overlapping_subnets, exclude_bogon_subnets, exclude_subnets, load_subnets, random_ip_rng,
+    write_atomic_async, Loadable, Savable, Subnet,
+};
+use cdn_ip_tester::error;
+use cdn_ip_tester::error::{DeserializedError, ErrorKind, ReqwestError, Result, TokioError};
+use cdn_ip_tester::logger::{init_logger, no_color_env, LogFormat};
+use cdn_ip_tester::metrics::write_prometheus;
+use cdn_ip_tester::progress::{Progress, ProgressEvent, ProgressMode};
+use cdn_ip_tester::rtt::{do_test_rtt, RttTestOptions};
+use cdn_ip_tester::sing_box::{terminate_all, ReusableSingBox};
+use cdn_ip_tester::template::{Outbound, SingBoxConfig};
 
 const CONFIG_FILE_NAME: &str = "ip-tester.toml";
 const OUTBOUND_TEMPLATE_FILE_NAME: &str = "outbound-template.json";
 const SING_BOX_TEMPLATE_FILE_NAME: &str = "sing-box-template.json";
-const SING_BOX_CONFIG_FILE_NAME: &str = "sing-box-test-config.json";
-const RTT_RESULT_FILE_NAME: &str = "result.txt";
-const RTT_RESULT_CACHE_FILE_NAME: &str = "result_cache.toml";
-
-async fn do_test_rtt(
-    client: Client,
-    url: Url,
-    expected_body: String,
-) -> core::result::Result<u64, ReqwestError> {
-    let start = SystemTime::now();
-    let res = client
-        .get(url)
-        .send()
-        .await
-        .map_err(ReqwestError::network)?;
-
-    let body = res.text().await.map_err(ReqwestError::network)?;
-    if !body.contains(expected_body.as_str()) {
-        Err(ReqwestError::body_no_match(body, expected_body))?
-    }
-    Ok(SystemTime::now().duration_since(start).unwrap().as_millis() as u64)
-}
+const SING_BOX_CONFIG_FILE_STEM: &str = "sing-box-test-config";
+const RTT_RESULT_FILE_STEM: &str = "result";
+const RTT_RESULT_CACHE_FILE_NAME: &str = "result_cache.bin";
+/// Written by versions before the cache is saved with bincode
+const LEGACY_RTT_RESULT_CACHE_FILE_NAME: &str = "result_cache.toml";
+const SING_BOX_HEALTH_CHECK_INTERVAL: Duration = Duration::from_millis(500);
 
-async fn test_rtt(config: Arc<Config>, cdn_ip: IpAddr, idx: usize) -> Result<RttResult> {
+async fn test_rtt(
+    config: Arc<Config>,
+    cdn_body_matcher: BodyMatcher,
+    server_body_matcher: BodyMatcher,
+    rate_limiter: Option<Arc<DefaultDirectRateLimiter>>,
+    cdn_ip: IpAddr,
+    port: u16,
+) -> Result<RttResult> {
     let server_client = Client::builder()
         .proxy(
-            reqwest::Proxy::all(format!(
-                "socks5://{}:{}",
-                config.listen_ip,
-                config.port_base + idx as u16
-            ))
+            reqwest::Proxy::all(format!("socks5://{}:{port}", config.listen_ip))
             .map_err(ReqwestError::build)?,
         )
+        .user_agent(config.user_agent.as_str())
+        .connect_timeout(config.connection_timeout())
         .timeout(Duration::from_millis(config.max_rtt))
         .build()
         .map_err(ReqwestError::build)?;
 
     let server_url = Url::parse(config.server_url.as_str()).map_err(DeserializedError::from)?;
+
+    let cdn_headers = config.cdn_header_map()?;
+    let cdn_options = RttTestOptions {
+        body_matcher: cdn_body_matcher,
+        expected_status: config.cdn_expected_status(),
+        retry_count: config.retry_count,
+        retry_delay_ms: config.retry_delay_ms,
+        rtt_samples: config.rtt_samples,
+    };
+    let mut cdn_rtt_tasks = Vec::new();
+    for cdn_url in config.effective_cdn_urls() {
+        let (cdn_client, parsed_cdn_url) = build_cdn_client(&config, cdn_url, cdn_ip)?;
+        cdn_rtt_tasks.push((
+            cdn_url,
+            tokio::task::spawn(do_test_rtt(
+                cdn_client,
+                config.cdn_method(),
+                parsed_cdn_url,
+                cdn_headers.clone(),
+                cdn_options.clone(),
+                rate_limiter.clone(),
+            )),
+        ));
+    }
+    let server_rtt_task = tokio::task::spawn(do_test_rtt(
+        server_client,
+        Method::GET,
+        server_url,
+        HeaderMap::new(),
+        RttTestOptions {
+            body_matcher: server_body_matcher,
+            expected_status: config.server_expected_status(),
+            ..cdn_options
+        },
+        rate_limiter,
+    ));
+
+    // use the best cdn url, fail only if all cdn urls failed
+    let mut best_cdn_rtt: Option<(u64, Vec<u64>, &str)> = None;
+    let mut cdn_rtt_err = None;
+    for (cdn_url, cdn_rtt_task) in cdn_rtt_tasks {
+        match cdn_rtt_task.await.map_err(TokioError::from)? {
+            Ok(cdn_rtts) => {
+                let cdn_rtt = *cdn_rtts.iter().min().unwrap();
+                if best_cdn_rtt
+                    .as_ref()
+                    .is_none_or(|(best_cdn_rtt, _, _)| cdn_rtt < *best_cdn_rtt)
+                {
+                    best_cdn_rtt = Some((cdn_rtt, cdn_rtts, cdn_url));
+                }
+            }
+            Err(err) => cdn_rtt_err = Some(err),
+        }
+    }
+    let server_rtt_result = server_rtt_task.await.map_err(TokioError::from)?;
+
+    let (cdn_rtts, cdn_url) = match (best_cdn_rtt, cdn_rtt_err) {
+        (Some((_, cdn_rtts, cdn_url)), _) => (cdn_rtts, cdn_url),
+        (None, Some(err)) => Err(err)?,
+        (None, None) => unreachable!(),
+    };
+    let rtt_result = RttResult::from_samples(&server_rtt_result?, &cdn_rtts);
+    if config.cdn_urls.is_empty() {
+        Ok(rtt_result)
+    } else {
+        Ok(rtt_result.with_cdn_url_used(cdn_url.into()))
+    }
+}
+
+fn build_cdn_client(config: &Config, cdn_url: &str, cdn_ip: IpAddr) -> Result<(Client, Url)> {
     let cdn_ip_string = cdn_ip.to_string();
 
-    let cdn_url = if config.cdn_url.is_empty() {
+    let parsed_cdn_url = if cdn_url.is_empty() {
         Url::parse(format!("http://{}", cdn_ip_string).as_str()).map_err(DeserializedError::from)?
     } else {
-        Url::parse(config.cdn_url.as_str()).map_err(DeserializedError::from)?
+        Url::parse(cdn_url).map_err(DeserializedError::from)?
     };
-    let cdn_domain = if let Some(cdn_domain) = cdn_url.domain() {
+    let cdn_domain = if let Some(cdn_domain) = parsed_cdn_url.domain() {
         cdn_domain
-    } else if config.cdn_url.is_empty() {
+    } else if cdn_url.is_empty() {
         cdn_ip_string.as_str()
     } else {
         Err(DeserializedError::custom("Url must have domain, not IP"))?
     };
-    if cdn_url.scheme() != "http" && cdn_url.scheme() != "https" {
+    if parsed_cdn_url.scheme() != "http" && parsed_cdn_url.scheme() != "https" {
         Err(DeserializedError::custom(
             "Url scheme must be http or https",
         ))?
     }
-    let cdn_url_port = if let Some(cdn_url_port) = cdn_url.port_or_known_default() {
+    let cdn_url_port = if let Some(cdn_url_port) = parsed_cdn_url.port_or_known_default() {
         cdn_url_port
     } else {
         unreachable!()
@@ -96,145 +168,240 @@ async fn test_rtt(config: Arc<Config>, cdn_ip: IpAddr, idx: usize) -> Result<Rtt
 
     let cdn_client = Client::builder()
         .resolve_to_addrs(cdn_domain, &[SocketAddr::new(cdn_ip, cdn_url_port)])
+        .danger_accept_invalid_certs(config.tls_skip_verify)
+        .user_agent(config.user_agent.as_str())
+        .connect_timeout(config.connection_timeout())
         .timeout(Duration::from_millis(config.max_rtt))
         .build()
         .map_err(ReqwestError::build)?;
+    Ok((cdn_client, parsed_cdn_url))
+}
 
-    let cdn_expected_body = config.cdn_res_body.clone();
-    let cdn_rtt_task = tokio::task::spawn(do_test_rtt(cdn_client, cdn_url, cdn_expected_body));
-    let server_expected_body = config.server_res_body.clone();
-    let server_rtt_task =
-        tokio::task::spawn(do_test_rtt(server_client, server_url, server_expected_body));
-
-    let cdn_rtt_result = cdn_rtt_task.await.map_err(TokioError::from)?;
-    let server_rtt_result = server_rtt_task.await.map_err(TokioError::from)?;
+async fn test_rtts_with_sing_box(
+    config: Arc<Config>,
+    sing_box: Arc<Mutex<ReusableSingBox>>,
+    rate_limiter: Option<Arc<DefaultDirectRateLimiter>>,
+    sing_box_config: SingBoxConfig,
+    sing_box_config_path: String,
+    ips: Vec<IpInet>,
+    port_base: u16,
+) -> Result<Vec<Result<RttResult>>> {
+    // hold the lock during the batch so that sing box is not restarted under the tests
+    let mut sing_box = sing_box.lock().await;
+    if let Err(err) = sing_box
+        .update(&sing_box_config, &sing_box_config_path)
+        .await
+    {
+        error!("Can not start sing box process: {err}");
+        Err(err)?
+    }
 
-    Ok(RttResult::new(server_rtt_result?, cdn_rtt_result?))
+    // regex of the body matchers are compiled once per batch
+    let cdn_body_matcher = config.cdn_body_matcher()?;
+    let server_body_matcher = config.server_body_matcher()?;
+    let cancellation_token = CancellationToken::new();
+    let mut tasks = Vec::new();
+    let mut ret = Vec::new();
+    for (i, ip_inet) in ips.iter().enumerate() {
+        let config = config.clone();
+        let cancellation_token = cancellation_token.clone();
+        let test = test_rtt(
+            config,
+            cdn_body_matcher.clone(),
+            server_body_matcher.clone(),
+            rate_limiter.clone(),
+            ip_inet.address(),
+            port_base + i as u16,
+        );
+        tasks.push(tokio::task::spawn(async move {
+            tokio::select! {
+                res = test => Some(res),
+                _ = cancellation_token.cancelled() => None,
+            }
+        }));
+    }
+    let collect_results = async {
+        for task in tasks {
+            if let Some(res) = task.await.map_err(TokioError::from)? {
+                ret.push(res);
+            }
+        }
+        Ok::<_, error::Error>(())
+    };
+    // the remaining tests would only fail with connection refused once sing box exits
+    let health_check = async {
+        while sing_box.is_alive() {
+            tokio::time::sleep(SING_BOX_HEALTH_CHECK_INTERVAL).await;
+        }
+        cancellation_token.cancel();
+    };
+    tokio::select! {
+        res = collect_results => res?,
+        _ = health_check => {}
+    }
+    // the failed tests of the batch are caused by sing box instead of the ips
+    if cancellation_token.is_cancelled() || sing_box.crashed() {
+        Err(ErrorKind::process(io::Error::other(
+            "sing box exited during the batch",
+        )))?
+    }
+    Ok(ret)
 }
 
-struct SingBox {
-    child: Child,
+/// One [`ReusableSingBox`] for each of the `parallel_sing_box` port ranges.
+fn new_sing_boxes(config: &Config) -> Vec<Arc<Mutex<ReusableSingBox>>> {
+    (0..config.parallel_sing_box.max(1))
+        .map(|_| {
+            Arc::new(Mutex::new(ReusableSingBox::new(
+                &config.sing_box_path,
+                &config.sing_box_extra_args,
+                Duration::from_secs(config.startup_timeout_secs),
+            )))
+        })
+        .collect()
 }
 
-impl SingBox {
-    async fn new(config_file_name: &str) -> Result<Self> {
-        let mut child = Command::new("./sing-box")
-            .args(["run", "-c", config_file_name])
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .map_err(ErrorKind::process)?;
-        let mut tmp_buf = [0_u8];
-        if let Err(read_stdout_err) = child
-            .stderr
-            .as_mut()
-            .unwrap()
-            .read_exact(&mut tmp_buf)
-            .await
-        {
-            child.wait().await.map_err(ErrorKind::process)?;
-            let mut stderr_output = Vec::new();
-            child
-                .stderr
-                .as_mut()
-                .unwrap()
-                .read_to_end(&mut stderr_output)
-                .await
-                .map_err(ErrorKind::process)?;
-            let stderr_output_str = String::from_utf8(stderr_output.clone()).unwrap();
-            error!("{read_stdout_err}\noutput: \n{stderr_output_str}");
-            Err(ErrorKind::process(read_stdout_err))?
-        }
-        Ok(Self { child })
-    }
-}
-
-impl Drop for SingBox {
-    fn drop(&mut self) {
-        tokio::task::block_in_place(move || {
-            Handle::current().block_on(async {
-                if let Err(err) = self.child.kill().await {
-                    error!("self.child.kill failed: {err}");
-                } else {
-                    debug!("child kill!");
-                }
-            });
-        });
-    }
+/// The rate limiter shared by every test of the run, `None` without `--rate-limit`.
+fn new_rate_limiter(args: &Args, config: &Config) -> Option<Arc<DefaultDirectRateLimiter>> {
+    let rate_limit = args.rate_limit?;
+    // allow a full batch to start at once
+    let burst = NonZeroU32::new(config.max_connection_count as u32).unwrap_or(NonZeroU32::MIN);
+    // every sample and retry of the cdn and server requests takes a permit
+    info!("Rate limit: {rate_limit} http requests per second, burst {burst}");
+    Some(Arc::new(RateLimiter::direct(
+        Quota::per_second(rate_limit).allow_burst(burst),
+    )))
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn test_rtts(
     config: &Arc<Config>,
+    sing_boxes: &[Arc<Mutex<ReusableSingBox>>],
+    rate_limiter: Option<&Arc<DefaultDirectRateLimiter>>,
     sing_box_template: &SingBoxConfig,
     outbound_template: &Outbound,
     data_dir: &str,
     ignore_body_warning: bool,
-    progress_bar: &ProgressBar,
+    strict_mode: bool,
+    fail_fast: bool,
+    strict_warnings: &mut Vec<error::Error>,
+    progress_bar: &Progress,
     ips: &[IpInet],
 ) -> Result<Vec<Option<RttResult>>> {
-    let sing_box_config = sing_box_template.generate(
-        outbound_template,
-        &ips.iter()
-            .map(|ip_inet| ip_inet.address().to_string())
-            .collect::<Vec<String>>(),
-        config.listen_ip.clone(),
-        config.port_base,
-    );
-
-    let sing_box_config_path = format!("{data_dir}/{SING_BOX_CONFIG_FILE_NAME}");
-    sing_box_config.save(&sing_box_config_path)?;
-
-    let sing_box = match SingBox::new(&sing_box_config_path).await {
-        Ok(sing_box) => sing_box,
-        Err(err) => {
-            error!("Can not start sing box process: {err}");
-            Err(err)?
-        }
-    };
-
-    let mut tasks = Vec::new();
-    let mut ret = Vec::new();
-    for (i, &cdn_ip) in ips.iter().enumerate() {
-        let config = config.clone();
-        tasks.push(tokio::task::spawn(test_rtt(config, cdn_ip.address(), i)));
+    // each sing box process listens on its own port range
+    let parallel_sing_box = config.parallel_sing_box.max(1);
+    let chunk_size = ips.len().div_ceil(parallel_sing_box).max(1);
+    let mut sing_box_tasks = Vec::new();
+    for (i, chunk) in ips.chunks(chunk_size).enumerate() {
+        let port_base = config.port_base + (i * chunk_size) as u16;
+        let sing_box_config = sing_box_template.generate(
+            outbound_template,
+            &chunk
+                .iter()
+                .map(|ip_inet| ip_inet.address().to_string())
+                .collect::<Vec<String>>(),
+            config.listen_ip.clone(),
+            port_base,
+        );
+        sing_box_config.validate()?;
+        let sing_box_config_path = if parallel_sing_box == 1 {
+            format!("{data_dir}/{SING_BOX_CONFIG_FILE_STEM}.json")
+        } else {
+            format!("{data_dir}/{SING_BOX_CONFIG_FILE_STEM}-{i}.json")
+        };
+        sing_box_tasks.push(tokio::task::spawn(test_rtts_with_sing_box(
+            config.clone(),
+            sing_boxes[i].clone(),
+            rate_limiter.cloned(),
+            sing_box_config,
+            sing_box_config_path,
+            chunk.to_vec(),
+            port_base,
+        )));
     }
 
-    for (i, task) in tasks.iter_mut().enumerate() {
-        let res = task.await.map_err(TokioError::from)?;
+    // wait for all sing box processes before reporting any error
+    let mut sing_box_results = Vec::new();
+    for task in sing_box_tasks {
+        sing_box_results.push(task.await.map_err(TokioError::from)?);
+    }
 
-        match res {
-            Ok(rtt) => {
-                let log_str = format!("ip: {}, rtt: {:?}", ips[i], rtt);
-                progress_bar.println(log_str.as_str());
-                debug!("{log_str}");
-                ret.push(Some(rtt));
+    let mut ret = Vec::new();
+    for (sing_box_result, chunk) in sing_box_results.into_iter().zip(ips.chunks(chunk_size)) {
+        let sing_box_result = match sing_box_result {
+            // sing box failed to start or exited, every ip of the chunk is failed unless --fail-fast
+            Err(err) if !fail_fast && matches!(*err.0, ErrorKind::Process { .. }) => {
+                warn!("Skip {} ips as sing box failed: {err}", chunk.len());
+                ret.extend(chunk.iter().map(|_| None));
+                continue;
             }
-            Err(err) => {
-                if !ignore_body_warning {
-                    if let Some(ReqwestError::BodyNoMatch { .. }) =
-                        err.source().unwrap().downcast_ref()
-                    {
-                        warn!("ip: {} body unmatched: \n{}", ips[i], err);
-                    }
+            sing_box_result => sing_box_result?,
+        };
+        for res in sing_box_result {
+            let i = ret.len();
+            match res {
+                Ok(rtt) => {
+                    let log_str = format!("ip: {}, rtt: {:?}", ips[i], rtt);
+                    progress_bar.println(log_str.as_str());
+                    debug!("{log_str}");
+                    ret.push(Some(rtt));
                 }
+                Err(err) => {
+                    let body_no_match = matches!(
+                        err.source().unwrap().downcast_ref(),
+                        Some(ReqwestError::BodyNoMatch { .. })
+                    );
+                    if body_no_match {
+                        if strict_mode {
+                            strict_warnings.push(err);
+                        } else if !ignore_body_warning {
+                            warn!("ip: {} body unmatched: \n{}", ips[i], err);
+                        }
+                    }
 
-                // warn!("ip:{}, err:{:?}", ips[i], err);
+                    // warn!("ip:{}, err:{:?}", ips[i], err);
 
-                ret.push(None);
+                    ret.push(None);
+                }
             }
         }
     }
-    drop(sing_box);
     Ok(ret)
 }
 
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Write the default config to {data_dir}/ip-tester.toml
+    Config,
+    /// Check config, templates, result and cache files in {data_dir}
+    Validate,
+    /// Merge result files, keep the better result if an ip appears in multiple files
+    Merge {
+        #[arg(long, num_args = 1.., required = true)]
+        input_files: Vec<String>,
+        #[arg(long)]
+        output_file: String,
+    },
+    /// Compare two result files
+    Diff {
+        #[arg(long)]
+        before: String,
+        #[arg(long)]
+        after: String,
+    },
+}
+
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Commands>,
     #[arg(long)]
     ignore_body_warning: bool,
-    #[arg(long)]
-    ip_file: String,
+    #[arg(long, conflicts_with = "ignore_body_warning")]
+    strict_mode: bool,
+    #[arg(long, required_unless_present_any = ["stats", "sort_by", "re_test"])]
+    ip_file: Option<String>,
     #[arg(long, default_value_t = 0)]
     subnet_count: usize,
     #[arg(long)]
@@ -245,44 +412,1109 @@ struct Args {
     auto_skip: bool,
     #[arg(long, default_value_t = 10)]
     enable_threshold: usize,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output_format: OutputFormat,
+    #[arg(long, value_enum)]
+    sort_by: Option<SortKey>,
+    #[arg(long)]
+    top_n: Option<usize>,
+    #[arg(long)]
+    max_server_rtt: Option<u64>,
+    #[arg(long)]
+    max_cdn_rtt: Option<u64>,
+    #[arg(long)]
+    merge_file: Option<String>,
+    #[arg(long)]
+    cache_ttl_hours: Option<u64>,
+    #[arg(long)]
+    exclude_file: Option<String>,
+    #[arg(long)]
+    random_order: bool,
+    #[arg(long)]
+    dedup_subnets: bool,
+    #[arg(long)]
+    blacklist_file: Option<String>,
+    #[arg(long)]
+    keep_backup: bool,
+    #[arg(long)]
+    dry_run: bool,
+    #[arg(long)]
+    stats: bool,
+    #[arg(long, conflicts_with = "stats")]
+    re_test: Option<usize>,
+    #[arg(long, value_enum, default_value_t = LogFormat::Pretty)]
+    log_format: LogFormat,
+    #[arg(long)]
+    log_file: Option<String>,
+    #[arg(long, default_value_t = LevelFilter::Debug, requires = "log_file")]
+    log_file_level: LevelFilter,
+    #[arg(long, conflicts_with = "quiet")]
+    progress_json: bool,
+    #[arg(long, default_value_t = 20)]
+    histogram_buckets: usize,
+    #[arg(long)]
+    no_summary: bool,
+    #[arg(long)]
+    subnet_report: bool,
+    #[arg(long, default_value_t = 10)]
+    top: usize,
+    #[arg(long)]
+    quiet: bool,
+    #[arg(long)]
+    no_color: bool,
+    #[arg(long)]
+    output_file: Option<String>,
+    #[arg(long, conflicts_with = "output_file")]
+    output_file_template: Option<String>,
+    #[arg(long)]
+    metrics_output: Option<String>,
+    #[arg(long)]
+    seed: Option<u64>,
+    #[arg(long)]
+    max_errors: Option<usize>,
+    #[arg(long)]
+    error_rate_threshold: Option<f64>,
+    #[arg(long, default_value_t = 100, requires = "error_rate_threshold")]
+    error_rate_min_samples: usize,
+    #[arg(long)]
+    fail_fast: bool,
+    #[arg(long)]
+    coverage_report: bool,
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    density: Option<usize>,
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    stride: Option<usize>,
+    #[arg(long)]
+    skip_bogons: bool,
+    #[arg(long, default_value_t = 1, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    checkpoint_every: usize,
+    #[arg(long)]
+    group_by_subnet: bool,
+    #[arg(long)]
+    watch: bool,
+    #[arg(long, default_value_t = 3600, requires = "watch")]
+    watch_interval_secs: u64,
+    #[arg(long)]
+    split_subnets_larger_than: Option<u8>,
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    parallel_subnets: Option<usize>,
+    #[arg(long)]
+    rate_limit: Option<NonZeroU32>,
+    #[arg(long, default_value_t = 20, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    circuit_breaker_threshold: usize,
+    #[arg(long, num_args = 1, action = ArgAction::Append, allow_hyphen_values = true)]
+    sing_box_args: Vec<String>,
+    #[arg(long, overrides_with = "no_resume_check")]
+    resume_check: bool,
+    #[arg(long, overrides_with = "resume_check")]
+    no_resume_check: bool,
+}
+
+impl Args {
+    /// `--output-file`, or `{data_dir}/result.{extension}` by default.
+    fn rtt_result_file_name(&self) -> String {
+        self.output_file.clone().unwrap_or_else(|| {
+            format!(
+                "{}/{RTT_RESULT_FILE_STEM}.{}",
+                self.data_dir,
+                self.output_format.extension()
+            )
+        })
+    }
+
+    /// `--resume-check` is on unless `--no-resume-check` is the last one passed.
+    fn resume_check(&self) -> bool {
+        self.resume_check || !self.no_resume_check
+    }
+
+    fn progress_mode(&self) -> ProgressMode {
+        if self.quiet {
+            ProgressMode::Quiet
+        } else if self.progress_json {
+            ProgressMode::Json
+        } else {
+            ProgressMode::Bar
+        }
+    }
+}
+
+/// Durations of the last [`BatchTimer::WINDOW`] batches, the eta of indicatif averages over the
+/// whole run and is far off at the start of a run.
+#[derive(Clone, Default)]
+struct BatchTimer {
+    /// (duration, ip count) of each batch
+    batches: Arc<std::sync::Mutex<VecDeque<(Duration, usize)>>>,
+}
+
+impl BatchTimer {
+    const WINDOW: usize = 20;
+
+    fn record(&self, duration: Duration, ip_count: usize) {
+        let mut batches = self.batches.lock().unwrap();
+        if batches.len() == Self::WINDOW {
+            batches.pop_front();
+        }
+        batches.push_back((duration, ip_count));
+    }
+
+    /// `None` before the first batch is done.
+    fn eta(&self, remaining: u64) -> Option<Duration> {
+        let batches = self.batches.lock().unwrap();
+        let (duration, ip_count) = batches.iter().fold(
+            (Duration::ZERO, 0),
+            |(total_duration, total_ip_count), (duration, ip_count)| {
+                (total_duration + *duration, total_ip_count + ip_count)
+            },
+        );
+        if ip_count == 0 {
+            return None;
+        }
+        Some(duration.mul_f64(remaining as f64 / ip_count as f64))
+    }
+
+    /// `{rolling_eta}` of the progress bar template, formatted like `{elapsed_precise}`.
+    fn eta_tracker(&self) -> impl Fn(&ProgressState, &mut dyn fmt::Write) + Clone + Send + Sync {
+        let batch_timer = self.clone();
+        move |state: &ProgressState, w: &mut dyn fmt::Write| {
+            let remaining = state.len().unwrap_or_default().saturating_sub(state.pos());
+            let _ = match batch_timer.eta(remaining) {
+                Some(eta) => {
+                    let secs = eta.as_secs();
+                    write!(
+                        w,
+                        "{:02}:{:02}:{:02}",
+                        secs / 3600,
+                        secs / 60 % 60,
+                        secs % 60
+                    )
+                }
+                None => write!(w, "--:--:--"),
+            };
+        }
+    }
+}
+
+/// Nearest-rank percentile of sorted `values`.
+fn percentile(values: &[u64], pct: usize) -> u64 {
+    let rank = (values.len() * pct).div_ceil(100).max(1);
+    values[rank - 1]
+}
+
+fn print_rtt_stats(name: &str, mut rtts: Vec<u64>) {
+    rtts.sort_unstable();
+    println!(
+        "{name}: min {} ms, max {} ms, mean {} ms, p50 {} ms, p95 {} ms, p99 {} ms",
+        rtts[0],
+        rtts[rtts.len() - 1],
+        rtts.iter().sum::<u64>() / rtts.len() as u64,
+        percentile(&rtts, 50),
+        percentile(&rtts, 95),
+        percentile(&rtts, 99)
+    );
+}
+
+fn print_stats(rtt_results: &RttResults) {
+    println!("total ip count: {}", rtt_results.len());
+    if rtt_results.is_empty() {
+        return;
+    }
+    print_rtt_stats(
+        "server_rtt",
+        rtt_results
+            .iter()
+            .map(|(_, rtt_result)| rtt_result.server_rtt())
+            .collect(),
+    );
+    print_rtt_stats(
+        "cdn_rtt",
+        rtt_results
+            .iter()
+            .map(|(_, rtt_result)| rtt_result.cdn_rtt())
+            .collect(),
+    );
+    let subnet_count = rtt_results
+        .iter()
+        .map(|(ip_inet, _)| ip_inet.network())
+        .collect::<HashSet<_>>()
+        .len();
+    println!("subnet count: {subnet_count}");
+    println!("top 5 ips:");
+    for (ip_inet, rtt_result) in rtt_results.get_top_n(5) {
+        println!(
+            "  {}, server_rtt: {} ms, cdn_rtt: {} ms",
+            ip_inet.address(),
+            rtt_result.server_rtt(),
+            rtt_result.cdn_rtt()
+        );
+    }
+}
+
+/// Min, max and percentiles of one kind of rtt, in ms.
+#[derive(Debug, Default, Clone, Copy)]
+struct RttPercentiles {
+    min: u64,
+    p50: u64,
+    p95: u64,
+    p99: u64,
+    max: u64,
+}
+
+impl RttPercentiles {
+    fn new(mut rtts: Vec<u64>) -> Self {
+        if rtts.is_empty() {
+            return Self::default();
+        }
+        rtts.sort_unstable();
+        Self {
+            min: rtts[0],
+            p50: percentile(&rtts, 50),
+            p95: percentile(&rtts, 95),
+            p99: percentile(&rtts, 99),
+            max: rtts[rtts.len() - 1],
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PercentilesReport {
+    count: usize,
+    server_rtt: RttPercentiles,
+    cdn_rtt: RttPercentiles,
+}
+
+impl Display for PercentilesReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.count == 0 {
+            return writeln!(f, "no rtt results");
+        }
+        writeln!(f, "summary of {} rtt results (ms):", self.count)?;
+        writeln!(
+            f,
+            "{:<12}{:>8}{:>8}{:>8}{:>8}{:>8}",
+            "", "min", "p50", "p95", "p99", "max"
+        )?;
+        for (name, rtt) in [("server_rtt", self.server_rtt), ("cdn_rtt", self.cdn_rtt)] {
+            writeln!(
+                f,
+                "{name:<12}{:>8}{:>8}{:>8}{:>8}{:>8}",
+                rtt.min, rtt.p50, rtt.p95, rtt.p99, rtt.max
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn percentiles(results: &RttResults) -> PercentilesReport {
+    PercentilesReport {
+        count: results.len(),
+        server_rtt: RttPercentiles::new(
+            results
+                .iter()
+                .map(|(_, rtt_result)| rtt_result.server_rtt())
+                .collect(),
+        ),
+        cdn_rtt: RttPercentiles::new(
+            results
+                .iter()
+                .map(|(_, rtt_result)| rtt_result.cdn_rtt())
+                .collect(),
+        ),
+    }
+}
+
+fn format_subnet_stat(subnet: &Subnet, (success_count, total_count): (usize, usize)) -> String {
+    format!(
+        "Subnet {}: {success_count}/{total_count} ({}%)",
+        subnet.cidr,
+        success_count * 100 / total_count.max(1)
+    )
+}
+
+/// Print tested subnets from the highest success rate to the lowest.
+fn print_subnet_report(subnets: &[Subnet], subnet_stats: &HashMap<usize, (usize, usize)>) {
+    let mut subnet_stats: Vec<(usize, (usize, usize))> = subnet_stats
+        .iter()
+        .map(|(idx, stat)| (*idx, *stat))
+        .collect();
+    // compare success rates without dividing
+    subnet_stats.sort_by(
+        |(idx_a, (success_a, total_a)), (idx_b, (success_b, total_b))| {
+            (success_b * total_a)
+                .cmp(&(success_a * total_b))
+                .then(total_b.cmp(total_a))
+                .then(idx_a.cmp(idx_b))
+        },
+    );
+    println!("subnet report:");
+    for (idx, stat) in subnet_stats {
+        println!("  {}", format_subnet_stat(&subnets[idx], stat));
+    }
+}
+
+/// Print how many ips of each subnet have results, from the lowest coverage to the highest.
+fn print_coverage_report(subnets: &[Subnet], rtt_results: &RttResults, max_subnet_len: usize) {
+    let mut coverages: Vec<(&Subnet, usize, usize)> = subnets
+        .iter()
+        .map(|subnet| {
+            let tested_count = rtt_results
+                .iter()
+                .filter(|(ip_inet, _)| subnet.contains(ip_inet.address()))
+                .count();
+            (subnet, tested_count, subnet.len().min(max_subnet_len))
+        })
+        .collect();
+    // compare coverages without dividing
+    coverages.sort_by(|(_, tested_a, total_a), (_, tested_b, total_b)| {
+        (tested_a * total_b).cmp(&(tested_b * total_a))
+    });
+    println!("coverage report:");
+    println!(
+        "  {:<43}  {:>10}  {:>10}  {:>8}",
+        "Subnet", "tested", "total", "coverage"
+    );
+    for (subnet, tested_count, total_count) in coverages {
+        println!(
+            "  {:<43}  {tested_count:>10}  {total_count:>10}  {:>7}%",
+            subnet.cidr.to_string(),
+            tested_count * 100 / total_count.max(1)
+        );
+    }
+}
+
+/// Log the subnets which have results from the best score to the worst, see [`Subnet::score`].
+fn log_subnet_scores(subnets: &[Subnet], rtt_results: &RttResults) {
+    // scoring checks every result against every subnet
+    if !log_enabled!(Level::Debug) {
+        return;
+    }
+    let mut subnet_scores: Vec<(&Subnet, u64)> = subnets
+        .iter()
+        .filter_map(|subnet| Some((subnet, subnet.score(rtt_results)?)))
+        .collect();
+    subnet_scores.sort_by_key(|(_, score)| *score);
+    for (subnet, score) in subnet_scores {
+        debug!("Subnet {} score: {score}", subnet.cidr);
+    }
+}
+
+/// Post a summary of the run to `webhook_url`, failures are only logged.
+async fn send_webhook(
+    webhook_url: &str,
+    rtt_results: &RttResults,
+    total_tested: usize,
+    total_success: usize,
+    duration: Duration,
+) {
+    let top_5: Vec<serde_json::Value> = rtt_results
+        .get_top_n(5)
+        .into_iter()
+        .map(|(ip_inet, rtt_result)| {
+            serde_json::json!({
+                "ip": ip_inet.address().to_string(),
+                "server_rtt": rtt_result.server_rtt(),
+                "cdn_rtt": rtt_result.cdn_rtt(),
+            })
+        })
+        .collect();
+    let body = serde_json::json!({
+        "total_tested": total_tested,
+        "total_success": total_success,
+        "top_5": top_5,
+        "duration_secs": duration.as_secs(),
+    });
+    let res = match Client::builder().timeout(Duration::from_secs(10)).build() {
+        Ok(client) => client.post(webhook_url).json(&body).send().await,
+        Err(err) => Err(err),
+    };
+    match res.and_then(|res| res.error_for_status()) {
+        Ok(_) => info!("Results summary posted to webhook {webhook_url}"),
+        Err(err) => warn!("Can not post results summary to webhook {webhook_url}: {err}"),
+    }
+}
+
+fn print_top_table(results: &RttResults, n: usize) {
+    if results.is_empty() || n == 0 {
+        return;
+    }
+    println!(
+        "{:>4}  {:<39}  {:>10}  {:>10}",
+        "#", "IP", "server_rtt", "cdn_rtt"
+    );
+    for (i, (ip_inet, rtt_result)) in results.get_top_n(n).into_iter().enumerate() {
+        println!(
+            "{:>4}  {:<39}  {:>10}  {:>10}",
+            i + 1,
+            ip_inet.address(),
+            rtt_result.server_rtt(),
+            rtt_result.cdn_rtt()
+        );
+    }
+}
+
+const HISTOGRAM_WIDTH: usize = 50;
+
+/// Print a bar chart of server_rtt, `[0, max server_rtt]` is split into `buckets` equal-width bins.
+fn print_rtt_histogram(results: &RttResults, buckets: usize) {
+    if results.is_empty() || buckets == 0 {
+        return;
+    }
+    let max_rtt = results
+        .iter()
+        .map(|(_, rtt_result)| rtt_result.server_rtt())
+        .max()
+        .unwrap();
+    let bucket_width = (max_rtt + 1).div_ceil(buckets as u64).max(1);
+    let mut counts = vec![0_usize; buckets];
+    for (_, rtt_result) in results.iter() {
+        let idx = ((rtt_result.server_rtt() / bucket_width) as usize).min(buckets - 1);
+        counts[idx] += 1;
+    }
+    let max_count = *counts.iter().max().unwrap();
+    println!("server_rtt histogram:");
+    for (i, count) in counts.iter().enumerate() {
+        let start = i as u64 * bucket_width;
+        let bar = "#".repeat(count * HISTOGRAM_WIDTH / max_count);
+        println!(
+            "{start:>6} - {:<6} ms |{bar:<width$}| {count}",
+            start + bucket_width - 1,
+            width = HISTOGRAM_WIDTH
+        );
+    }
+}
+
+/// Print the rtt change of each ip, the largest server_rtt change first.
+fn diff_results(before: &RttResults, after: &RttResults) {
+    let mut changed = Vec::new();
+    let mut removed = Vec::new();
+    for (ip_inet, before_rtt_result) in before.iter() {
+        match after.get(ip_inet) {
+            Some(after_rtt_result) => changed.push((ip_inet, before_rtt_result, after_rtt_result)),
+            None => removed.push(ip_inet),
+        }
+    }
+    let server_rtt_change = |before_rtt_result: &RttResult, after_rtt_result: &RttResult| {
+        after_rtt_result.server_rtt() as i64 - before_rtt_result.server_rtt() as i64
+    };
+    changed.sort_by_key(|(_, before_rtt_result, after_rtt_result)| {
+        std::cmp::Reverse(server_rtt_change(before_rtt_result, after_rtt_result).abs())
+    });
+
+    for (ip_inet, before_rtt_result, after_rtt_result) in changed {
+        println!(
+            "ip: {ip_inet}  server_rtt: {}→{} ({:+}ms)  cdn_rtt: {}→{} ({:+}ms)",
+            before_rtt_result.server_rtt(),
+            after_rtt_result.server_rtt(),
+            server_rtt_change(before_rtt_result, after_rtt_result),
+            before_rtt_result.cdn_rtt(),
+            after_rtt_result.cdn_rtt(),
+            after_rtt_result.cdn_rtt() as i64 - before_rtt_result.cdn_rtt() as i64
+        );
+    }
+    for ip_inet in removed {
+        println!("removed: {ip_inet}");
+    }
+    for (ip_inet, _) in after.iter() {
+        if before.get(ip_inet).is_none() {
+            println!("added: {ip_inet}");
+        }
+    }
+}
+
+async fn merge_result_files(args: &Args, input_files: &[String], output_file: &str) -> Result<()> {
+    let mut rtt_results = RttResults::default();
+    rtt_results.sort_by_key(args.sort_by.unwrap_or_default());
+    let mut ip_file_counts: HashMap<IpInet, usize> = HashMap::new();
+    for input_file in input_files {
+        let input_rtt_results = match args.output_format.load_async(input_file).await {
+            Ok(input_rtt_results) => input_rtt_results,
+            Err(err) => {
+                error!("Can not load rtt result from {input_file}: {err}");
+                return Err(err);
+            }
+        };
+        info!(
+            "Load {} rtt results from {input_file}",
+            input_rtt_results.len()
+        );
+        for (ip_inet, _) in input_rtt_results.iter() {
+            *ip_file_counts.entry(*ip_inet).or_default() += 1;
+        }
+        rtt_results.merge(input_rtt_results);
+    }
+    rtt_results.commit();
+    rtt_results
+        .with_format(args.output_format)
+        .save_async(output_file)
+        .await?;
+    info!(
+        "Merge {} ips appeared in multiple files, write {} unique ips to {output_file}",
+        ip_file_counts.values().filter(|count| **count > 1).count(),
+        rtt_results.len()
+    );
+    Ok(())
+}
+
+fn print_validate_result(path: &str, res: Result<()>) -> bool {
+    match res {
+        Ok(()) => {
+            println!("PASS {path}");
+            true
+        }
+        Err(err) => {
+            println!("FAIL {path}\n{err}");
+            false
+        }
+    }
+}
+
+/// Validate every data file and print the result, return the count of failed files.
+fn validate_data_files(args: &Args) -> usize {
+    let config_path = format!("{}/{CONFIG_FILE_NAME}", args.data_dir);
+    let outbound_template_path = format!("{}/{OUTBOUND_TEMPLATE_FILE_NAME}", args.data_dir);
+    let sing_box_template_path = format!("{}/{SING_BOX_TEMPLATE_FILE_NAME}", args.data_dir);
+    let rtt_result_file_name = args.rtt_result_file_name();
+    let rtt_result_cache_file_name = format!("{}/{RTT_RESULT_CACHE_FILE_NAME}", args.data_dir);
+    let mut results = Vec::new();
+
+    let config = (|| -> Result<Config> {
+        let mut config = Config::load(&config_path)?;
+        config.apply_env_overrides()?;
+        config.validate()?;
+        Ok(config)
+    })();
+    let max_subnet_len = config
+        .as_ref()
+        .map_or(Config::default().max_subnet_len, |config| {
+            config.max_subnet_len
+        });
+    results.push(print_validate_result(&config_path, config.map(|_| ())));
+
+    results.push(print_validate_result(
+        &outbound_template_path,
+        Outbound::load(&outbound_template_path).and_then(|outbound| outbound.validate()),
+    ));
+    results.push(print_validate_result(
+        &sing_box_template_path,
+        SingBoxConfig::load(&sing_box_template_path)
+            .and_then(|sing_box_template| sing_box_template.validate()),
+    ));
+
+    // the saved result must survive a save and load round trip
+    results.push(print_validate_result(
+        &rtt_result_file_name,
+        (|| -> Result<()> {
+            let rtt_results = args.output_format.load(&rtt_result_file_name)?;
+            let reparsed_rtt_results = args
+                .output_format
+                .parse(&rtt_results.with_format(args.output_format).to_string()?)?;
+            if rtt_results.len() != reparsed_rtt_results.len() {
+                Err(DeserializedError::custom(
+                    format!(
+                        "{} rtt results are loaded but {} are reparsed",
+                        rtt_results.len(),
+                        reparsed_rtt_results.len()
+                    )
+                    .as_str(),
+                ))?
+            }
+            Ok(())
+        })(),
+    ));
+
+    // cache bounds can only be checked when the ip file is known
+    results.push(print_validate_result(
+        &rtt_result_cache_file_name,
+        (|| -> Result<()> {
+            let rtt_result_cache = RttResultCache::load(&rtt_result_cache_file_name)?;
+            if let Some(ip_file) = &args.ip_file {
+                let subnets = load_subnets(ip_file, args.strict_mode)?;
+                let max_subnet_len = subnets
+                    .iter()
+                    .map(Subnet::len)
+                    .max()
+                    .unwrap_or(0)
+                    .min(max_subnet_len);
+                if rtt_result_cache.current_subnet >= subnets.len() {
+                    Err(DeserializedError::custom(
+                        format!(
+                            "current_subnet: {}, but subnets.len(): {}",
+                            rtt_result_cache.current_subnet,
+                            subnets.len()
+                        )
+                        .as_str(),
+                    ))?
+                }
+                if rtt_result_cache.current_subnet_start >= max_subnet_len {
+                    Err(DeserializedError::custom(
+                        format!(
+                            "current_subnet_start: {}, but max_subnet_len: {}",
+                            rtt_result_cache.current_subnet_start, max_subnet_len
+                        )
+                        .as_str(),
+                    ))?
+                }
+            }
+            Ok(())
+        })(),
+    ));
+
+    results.iter().filter(|pass| !**pass).count()
+}
+
+/// Generate a sing box config for dummy ips without writing it or starting sing box.
+fn dry_run(
+    config: &Config,
+    sing_box_template: &SingBoxConfig,
+    outbound_template: &Outbound,
+) -> Result<()> {
+    let sing_box_config = sing_box_template.generate(
+        outbound_template,
+        &["192.0.2.1".into(), "192.0.2.2".into()],
+        config.listen_ip.clone(),
+        config.port_base,
+    );
+    sing_box_config.validate()?;
+    sing_box_config.to_string()?;
+    Ok(())
+}
+
+/// Make sure sing box can bind every port of `[port_base, port_base + max_connection_count)`.
+fn check_ports(config: &Config) -> Result<()> {
+    // listen_ip is checked by Config::validate
+    let listen_ip = config
+        .listen_ip
+        .parse::<IpAddr>()
+        .map_err(DeserializedError::from)?;
+    let mut conflicting_ports = Vec::new();
+    for i in 0..config.max_connection_count {
+        let port = config.port_base + i as u16;
+        // the listener is dropped right away so the port is released for sing box
+        if TcpListener::bind(SocketAddr::new(listen_ip, port)).is_err() {
+            error!("Port {port} is already in use — is another instance running?");
+            conflicting_ports.push(port);
+        }
+    }
+    if !conflicting_ports.is_empty() {
+        Err(ErrorKind::config(format!(
+            "ports {conflicting_ports:?} of {}:[{}, {}) are already in use",
+            config.listen_ip,
+            config.port_base,
+            config.port_base as usize + config.max_connection_count
+        )))?
+    }
+    Ok(())
+}
+
+/// Re-test the best `re_test_count` ips of the result file, the rtt result cache is untouched.
+async fn re_test(
+    args: &Args,
+    config: &Arc<Config>,
+    sing_box_template: &SingBoxConfig,
+    outbound_template: &Outbound,
+    re_test_count: usize,
+) -> Result<()> {
+    let rtt_result_file_name = args.rtt_result_file_name();
+    let mut rtt_results = match args.output_format.load_async(&rtt_result_file_name).await {
+        Ok(rtt_results) => rtt_results,
+        Err(err) => {
+            error!("Can not load rtt result from {rtt_result_file_name}: {err}");
+            return Err(err);
+        }
+    };
+    rtt_results.set_score_weights(config.score_weights());
+    rtt_results.sort_by_key(args.sort_by.unwrap_or(config.sort_by));
+    let ips: Vec<IpInet> = rtt_results
+        .get_top_n(re_test_count)
+        .into_iter()
+        .map(|(ip_inet, _)| *ip_inet)
+        .collect();
+    // results are keyed by the original ip, test the host ip of each one
+    let host_ips: Vec<IpInet> = RttResults::from_ips(&ips)
+        .iter()
+        .filter_map(|subnet| subnet.get_ip(0))
+        .collect();
+    info!(
+        "Re-test {} rtt results from {rtt_result_file_name}",
+        ips.len()
+    );
+
+    let progress_bar = Progress::new(ips.len() as u64, args.progress_mode());
+    let sing_boxes = new_sing_boxes(config);
+    let rate_limiter = new_rate_limiter(args, config);
+    let mut success_count = 0;
+    for (ips, host_ips) in ips
+        .chunks(config.max_connection_count)
+        .zip(host_ips.chunks(config.max_connection_count))
+    {
+        let mut strict_warnings = Vec::new();
+        let test_res = test_rtts(
+            config,
+            &sing_boxes,
+            rate_limiter.as_ref(),
+            sing_box_template,
+            outbound_template,
+            args.data_dir.as_str(),
+            args.ignore_body_warning,
+            args.strict_mode,
+            args.fail_fast,
+            &mut strict_warnings,
+            &progress_bar,
+            host_ips,
+        )
+        .await?;
+        if args.strict_mode && !strict_warnings.is_empty() {
+            for warning in &strict_warnings {
+                error!("{warning}");
+            }
+            Err(ErrorKind::strict(format!(
+                "{} body unmatched warnings in re-test",
+                strict_warnings.len()
+            )))?
+        }
+        // keep the old result of the ips which fail this time
+        for (ip_inet, rtt) in ips.iter().zip(test_res) {
+            if let Some(rtt) = rtt {
+                success_count += 1;
+                rtt_results.replace_result(*ip_inet, rtt);
+            }
+        }
+        progress_bar.inc(ips.len() as u64);
+    }
+    progress_bar.finish();
+    rtt_results.commit();
+    rtt_results
+        .with_format(args.output_format)
+        .save_async(&rtt_result_file_name)
+        .await?;
+    info!(
+        "Re-test finished, {success_count}/{} ips updated",
+        ips.len()
+    );
+    Ok(())
+}
+
+/// Check `--max-errors` and `--error-rate-threshold`, return the reason to abort the run.
+fn too_many_errors(args: &Args, total_tested: usize, total_success: usize) -> Option<String> {
+    let error_count = total_tested - total_success;
+    if let Some(max_errors) = args.max_errors {
+        if error_count >= max_errors {
+            return Some(format!(
+                "{error_count} errors reach --max-errors {max_errors}"
+            ));
+        }
+    }
+    if let Some(error_rate_threshold) = args.error_rate_threshold {
+        // the first batches may fail by chance, wait for enough samples
+        if total_tested > 0 && total_tested >= args.error_rate_min_samples {
+            let error_rate = error_count as f64 * 100.0 / total_tested as f64;
+            if error_rate > error_rate_threshold {
+                return Some(format!(
+                    "error rate {error_rate:.2}% ({error_count}/{total_tested}) exceeds --error-rate-threshold {error_rate_threshold}%"
+                ));
+            }
+        }
+    }
+    None
+}
+
+/// `(YYYY-MM-DD, HH-MM-SS)` of a unix timestamp in UTC.
+fn format_utc(timestamp: u64) -> (String, String) {
+    // civil_from_days of http://howardhinnant.github.io/date_algorithms.html
+    let z = (timestamp / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let secs = timestamp % 86400;
+    (
+        format!("{year:04}-{month:02}-{day:02}"),
+        format!(
+            "{:02}-{:02}-{:02}",
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60
+        ),
+    )
+}
+
+/// Replace `{date}`, `{datetime}` and `{run_id}` of `--output-file-template`, the time is the
+/// current UTC time so the template is resolved once at the start of the run.
+fn resolve_output_template(template: &str, cache: &RttResultCache) -> String {
+    let (date, time) = format_utc(unix_timestamp());
+    template
+        .replace("{datetime}", &format!("{date}_{time}"))
+        .replace("{date}", &date)
+        .replace("{run_id}", &cache.run_id.to_string())
+}
+
+fn limit_rtt_results(rtt_results: &mut RttResults, args: &Args) {
+    if args.max_server_rtt.is_some() || args.max_cdn_rtt.is_some() {
+        *rtt_results = rtt_results.filter_by_rtt(args.max_server_rtt, args.max_cdn_rtt);
+    }
+    if let Some(top_n) = args.top_n {
+        rtt_results.truncate(top_n);
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
-    pretty_env_logger::formatted_builder()
-        .filter_level(LevelFilter::Info)
-        .init();
+    let no_color = args.no_color || no_color_env();
+    if no_color {
+        console::set_colors_enabled(false);
+        console::set_colors_enabled_stderr(false);
+    }
+    init_logger(
+        args.log_format,
+        if args.quiet {
+            LevelFilter::Error
+        } else {
+            LevelFilter::Info
+        },
+        args.log_file
+            .as_deref()
+            .map(|log_file| (log_file, args.log_file_level)),
+        no_color,
+    )?;
+
+    if let Some(output_file) = &args.output_file {
+        let output_dir = Path::new(output_file)
+            .parent()
+            .filter(|output_dir| !output_dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        if !output_dir.is_dir() {
+            Err(ErrorKind::config(format!(
+                "directory of output file {output_file:?} does not exist"
+            )))?
+        }
+    }
+
+    if args.group_by_subnet && args.output_format != OutputFormat::Text {
+        Err(ErrorKind::config(
+            "--group-by-subnet only works with --output-format text".into(),
+        ))?
+    }
 
     let config_path = format!("{}/{CONFIG_FILE_NAME}", args.data_dir);
-    let config: Arc<Config> = match Config::load(&config_path) {
-        Ok(config) => Arc::new(config),
+    if let Some(Commands::Config) = args.command {
+        if let Err(err) = Config::save_default(&config_path) {
+            error!("Unable to write default config to {config_path}\n{err}");
+            return Err(err);
+        }
+        info!("Default config written to {config_path}");
+        return Ok(());
+    }
+    if let Some(Commands::Merge {
+        input_files,
+        output_file,
+    }) = &args.command
+    {
+        return merge_result_files(&args, input_files, output_file).await;
+    }
+    if let Some(Commands::Diff { before, after }) = &args.command {
+        let mut rtt_results_list = Vec::new();
+        for path in [before, after] {
+            match args.output_format.load_async(path).await {
+                Ok(rtt_results) => rtt_results_list.push(rtt_results),
+                Err(err) => {
+                    error!("Can not load rtt result from {path}: {err}");
+                    return Err(err);
+                }
+            }
+        }
+        diff_results(&rtt_results_list[0], &rtt_results_list[1]);
+        return Ok(());
+    }
+    if let Some(Commands::Validate) = args.command {
+        let failed_count = validate_data_files(&args);
+        if failed_count != 0 {
+            Err(ErrorKind::config(format!(
+                "{failed_count} files failed validation"
+            )))?
+        }
+        return Ok(());
+    }
+    // only work on the existing result file without --ip-file
+    if args.stats || (args.ip_file.is_none() && args.re_test.is_none()) {
+        let rtt_result_file_name = args.rtt_result_file_name();
+        let mut rtt_results = match args.output_format.load_async(&rtt_result_file_name).await {
+            Ok(rtt_results) => rtt_results,
+            Err(err) => {
+                error!("Can not load rtt result from {rtt_result_file_name}: {err}");
+                return Err(err);
+            }
+        };
+        if args.stats {
+            print_stats(&rtt_results);
+            return Ok(());
+        }
+        // ip_file is required unless --stats or --sort-by is given
+        let sort_by = args.sort_by.unwrap();
+        let score_weights = Config::load_async(&config_path)
+            .await
+            .map(|config| config.score_weights())
+            .unwrap_or_default();
+        rtt_results.set_score_weights(score_weights);
+        rtt_results.sort_by_key(sort_by);
+        rtt_results
+            .with_format(args.output_format)
+            .save_async(&rtt_result_file_name)
+            .await?;
+        info!(
+            "Resort {} rtt results in {rtt_result_file_name} by {sort_by:?}",
+            rtt_results.len()
+        );
+        return Ok(());
+    }
+    let mut config = match Config::load_async(&config_path).await {
+        Ok(config) => config,
         Err(err) => {
             info!("Unable to load config from {config_path}\n{err}");
             return Err(err);
         }
     };
+    if let Err(err) = config.apply_env_overrides() {
+        error!("Unable to apply env overrides to config\n{err}");
+        return Err(err);
+    }
+    if !args.sing_box_args.is_empty() {
+        config.sing_box_extra_args = args.sing_box_args.clone();
+    }
+    let config = Arc::new(config);
+    if let Err(err) = config.validate() {
+        error!("Invalid config {config_path}\n{err}");
+        return Err(err);
+    }
+    let sort_key = args.sort_by.unwrap_or(config.sort_by);
+    if config.score_weights_ignored(sort_key) {
+        warn!(
+            "score_server_weight and score_cdn_weight only apply to sort_by = \"score\", ignore them when sorting by {sort_key:?}"
+        );
+    }
+    if !config.cdn_request_headers.is_empty() {
+        debug!(
+            "cdn_request_headers: {:?}",
+            config.redacted_cdn_request_headers()
+        );
+    }
+    if config.tls_skip_verify {
+        warn!("tls_skip_verify = true, certificates of cdn urls will not be verified");
+    }
 
     let outbound_template_path = format!("{}/{OUTBOUND_TEMPLATE_FILE_NAME}", args.data_dir);
-    let outbound_template = match Outbound::load(&outbound_template_path) {
+    let outbound_template = match Outbound::load_async(&outbound_template_path).await {
         Ok(outbound) => outbound,
         Err(err) => {
             info!("Unable to load outbound template from {outbound_template_path}\n{err}");
             return Err(err);
         }
     };
+    if let Err(err) = outbound_template.validate() {
+        error!("Invalid outbound template {outbound_template_path}\n{err}");
+        return Err(err);
+    }
 
-    let mut subnets: Vec<Subnet> = match HashSet::load(&args.ip_file) {
-        Ok(subnets) => subnets,
-        Err(err) => {
-            info!("Unable to load subnets from {}\n{err}", &args.ip_file);
-            return Err(err);
+    // --re-test does not need the subnet file
+    let ip_file = args.ip_file.clone().unwrap_or_default();
+    let mut subnets: Vec<Subnet> = if args.ip_file.is_some() {
+        match load_subnets(&ip_file, args.strict_mode) {
+            Ok(subnets) => subnets,
+            Err(err) => {
+                info!("Unable to load subnets from {ip_file}\n{err}");
+                return Err(err);
+            }
         }
+    } else {
+        Vec::new()
+    };
+
+    let overlap_count = check_overlapping_subnets(&mut subnets, args.dedup_subnets);
+    if overlap_count != 0 {
+        warn!(
+            "Found {overlap_count} overlapping subnets in {ip_file}, dedup_subnets: {}",
+            args.dedup_subnets
+        );
     }
-    .iter()
-    .map(Subnet::clone)
-    .collect();
 
+    let excluded_subnets = if let Some(exclude_file) = &args.exclude_file {
+        let excluded_subnets = match Vec::<Subnet>::load_async(exclude_file).await {
+            Ok(excluded_subnets) => excluded_subnets,
+            Err(err) => {
+                info!("Unable to load excluded subnets from {exclude_file}\n{err}");
+                return Err(err);
+            }
+        };
+        let excluded_count = exclude_subnets(&mut subnets, &excluded_subnets);
+        info!(
+            "Load {} excluded subnets from {exclude_file:?}, {excluded_count} subnets are excluded",
+            excluded_subnets.len()
+        );
+        excluded_subnets
+    } else {
+        Vec::new()
+    };
+
+    let bogon_subnets = if args.skip_bogons {
+        exclude_bogon_subnets(&mut subnets)
+    } else {
+        Vec::new()
+    };
+
+    if let Some(chunk_prefix_len) = args.split_subnets_larger_than {
+        let subnet_count = subnets.len();
+        subnets = subnets
+            .iter()
+            .map(|subnet| subnet.split(chunk_prefix_len))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+        info!(
+            "Split {subnet_count} subnets into {} subnets of at most /{chunk_prefix_len}",
+            subnets.len()
+        );
+    }
+
+    let blacklisted_subnets = if let Some(blacklist_file) = &args.blacklist_file {
+        match Vec::<Subnet>::load_async(blacklist_file).await {
+            Ok(blacklisted_subnets) => {
+                info!(
+                    "Load {} blacklisted ips from {blacklist_file:?}",
+                    blacklisted_subnets.len()
+                );
+                blacklisted_subnets
+            }
+            Err(err) => {
+                info!("Unable to load blacklist from {blacklist_file}\n{err}");
+                return Err(err);
+            }
+        }
+    } else {
+        Vec::new()
+    };
+
+    if args.subnet_count > subnets.len() {
+        error!(
+            "--subnet-count {} is larger than the {} subnets of {ip_file}",
+            args.subnet_count,
+            subnets.len()
+        );
+        Err(DeserializedError::custom(
+            format!(
+                "subnet_count: {}, but subnets.len(): {}",
+                args.subnet_count,
+                subnets.len()
+            )
+            .as_str(),
+        ))?
+    }
+    if args.subnet_count == 0 {
+        info!("--subnet-count is 0, test all {} subnets", subnets.len());
+    }
     let subnets = if args.subnet_count != 0 {
         &mut subnets[..args.subnet_count]
     } else {
@@ -297,14 +1529,15 @@ async fn main() -> Result<()> {
         .min(config.max_subnet_len);
 
     info!(
+        subnet_count = subnets.len(), max_subnet_len = max_subnet_len;
         "Load {} subnets from {:?} success. max_subnet_len: {}",
         subnets.len(),
-        args.ip_file,
+        ip_file,
         max_subnet_len
     );
 
     let sing_box_template_path = format!("{}/{SING_BOX_TEMPLATE_FILE_NAME}", args.data_dir);
-    let sing_box_template = match SingBoxConfig::load(&sing_box_template_path) {
+    let sing_box_template = match SingBoxConfig::load_async(&sing_box_template_path).await {
         Ok(sing_box_template) => sing_box_template,
         Err(err) => {
             info!(
@@ -314,38 +1547,70 @@ async fn main() -> Result<()> {
             return Err(err);
         }
     };
+    // every generated sing box config copies the dns section of the template
+    let sing_box_template = match &config.dns_server {
+        Some(dns_server) => sing_box_template.with_dns_server(dns_server),
+        None => sing_box_template,
+    };
+
+    if args.dry_run {
+        if let Err(err) = dry_run(&config, &sing_box_template, &outbound_template) {
+            error!("dry-run failed\n{err}");
+            return Err(err);
+        }
+        println!("dry-run OK");
+        return Ok(());
+    }
+
+    check_ports(&config)?;
+
+    if let Some(re_test_count) = args.re_test {
+        return re_test(
+            &args,
+            &config,
+            &sing_box_template,
+            &outbound_template,
+            re_test_count,
+        )
+        .await;
+    }
 
-    let mut rtt_results;
     let mut rtt_result_cache;
-    let rtt_result_file_name = format!("{}/{RTT_RESULT_FILE_NAME}", args.data_dir);
+    let mut rtt_result_file_name = args.rtt_result_file_name();
     let rtt_result_cache_file_name = format!("{}/{RTT_RESULT_CACHE_FILE_NAME}", args.data_dir);
+    let subnet_file_hash = if args.ip_file.is_some() {
+        subnet_file_hash(&ip_file)?
+    } else {
+        String::new()
+    };
 
     if args.no_cache {
         info!("no_cache = true, use default rtt result cache and default rtt result");
-        rtt_results = RttResults::default();
         rtt_result_cache = RttResultCache::default()
     } else {
-        rtt_results = match RttResults::load(&rtt_result_file_name) {
-            Ok(rtt_results) => {
-                info!(
-                    "Load {} rtt results from {rtt_result_file_name} success",
-                    rtt_results.len()
+        let legacy_rtt_result_cache_file_name =
+            format!("{}/{LEGACY_RTT_RESULT_CACHE_FILE_NAME}", args.data_dir);
+        let mut loaded_rtt_result_cache = RttResultCache::load_or_legacy_async(
+            &rtt_result_cache_file_name,
+            &legacy_rtt_result_cache_file_name,
+        )
+        .await?;
+        if let Some(cache_ttl_hours) = args.cache_ttl_hours {
+            loaded_rtt_result_cache.cache_ttl_hours = cache_ttl_hours;
+        }
+        rtt_result_cache = match loaded_rtt_result_cache {
+            rtt_result_cache if rtt_result_cache.is_expired() => {
+                warn!(
+                    "Rtt result cache created at {} is older than {} hours, create new rtt result cache.",
+                    rtt_result_cache.cache_created_at, rtt_result_cache.cache_ttl_hours
                 );
-                rtt_results
+                rtt_result_cache.next_run()
             }
-            Err(err) => {
-                if let ErrorKind::Fs { .. } = *err.0 {
-                    info!("Can not load rtt result. Create new rtt result.");
-                    RttResults::default()
-                } else {
-                    error!("Can not load rtt result: {err}");
-                    return Err(err);
+            rtt_result_cache => {
+                // the resume check explains why the cache does not fit the subnets
+                if args.resume_check() {
+                    rtt_result_cache.check_resume(subnets, &ip_file, &subnet_file_hash)?;
                 }
-            }
-        };
-
-        rtt_result_cache = match RttResultCache::load(&rtt_result_cache_file_name) {
-            Ok(rtt_result_cache) => {
                 if rtt_result_cache.current_subnet >= subnets.len() {
                     Err(DeserializedError::custom(format!( "Can not load rtt result cache. current_subnet: {}, but subnets.len(): {}", rtt_result_cache.current_subnet, subnets.len()).as_str()))?;
                 }
@@ -354,24 +1619,134 @@ async fn main() -> Result<()> {
                     Err(DeserializedError::custom(format!( "Can not load rtt result cache. current_subnet_start: {}, but max_subnet_len: {}", rtt_result_cache.current_subnet_start, max_subnet_len).as_str()))?;
                 }
                 info!("Load rtt result cache success: {rtt_result_cache:?}");
+                if rtt_result_cache.started_at != 0 {
+                    info!(
+                        "Resuming run started at {}, previously ran for {}s",
+                        rtt_result_cache.started_at, rtt_result_cache.elapsed_secs
+                    );
+                }
                 rtt_result_cache
             }
-
+        }
+    }
+    if let Some(output_file_template) = &args.output_file_template {
+        rtt_result_file_name = resolve_output_template(output_file_template, &rtt_result_cache);
+        info!(
+            "Write rtt results of run {} to {rtt_result_file_name}",
+            rtt_result_cache.run_id
+        );
+    }
+    let mut rtt_results = if args.no_cache {
+        RttResults::default()
+    } else {
+        let rtt_results = args
+            .output_format
+            .load_or_default_async(&rtt_result_file_name)
+            .await?;
+        info!(
+            "Load {} rtt results from {rtt_result_file_name} success",
+            rtt_results.len()
+        );
+        rtt_results
+    };
+    if let Some(cache_ttl_hours) = args.cache_ttl_hours {
+        rtt_result_cache.cache_ttl_hours = cache_ttl_hours;
+    }
+    rtt_result_cache.subnet_file_hash = subnet_file_hash;
+    let seed = args
+        .seed
+        .or(rtt_result_cache.seed)
+        .unwrap_or_else(|| StdRng::from_entropy().gen());
+    rtt_result_cache.seed = Some(seed);
+    info!("Random seed: {seed}, use --seed {seed} to reproduce the run");
+    if let Some(density) = args.density {
+        rtt_result_cache.density = density;
+    }
+    // a hand edited cache may have density 0
+    let density = rtt_result_cache.density.max(1);
+    rtt_result_cache.density = density;
+    if density > 1 {
+        info!("Test every {density}th ip of each subnet");
+    }
+    if let Some(stride) = args.stride {
+        rtt_result_cache.stride = stride;
+    }
+    let stride = rtt_result_cache.stride.max(1);
+    rtt_result_cache.stride = stride;
+    // each subnet visit takes this many consecutive ips
+    if let Some(parallel_subnets) = args.parallel_subnets {
+        rtt_result_cache.ips_per_subnet = (config.max_connection_count / parallel_subnets).max(1);
+    }
+    let ips_per_subnet = rtt_result_cache.ips_per_subnet.max(1);
+    rtt_result_cache.ips_per_subnet = ips_per_subnet;
+    // a batch could never take a whole subnet visit
+    if ips_per_subnet > config.max_connection_count {
+        Err(ErrorKind::config(format!(
+            "ips_per_subnet {ips_per_subnet} of the rtt result cache is greater than max_connection_count {}, use --parallel-subnets or --no-cache",
+            config.max_connection_count
+        )))?
+    }
+    rtt_results.set_score_weights(config.score_weights());
+    rtt_results.sort_by_key(args.sort_by.unwrap_or(config.sort_by));
+    if let Some(merge_file) = &args.merge_file {
+        let merge_rtt_results = match args.output_format.load_async(merge_file).await {
+            Ok(merge_rtt_results) => merge_rtt_results,
             Err(err) => {
-                if let ErrorKind::Fs { .. } = *err.0 {
-                    info!("Can not load rtt result cache. Create new rtt result cache.");
-                    RttResultCache::default()
-                } else {
-                    error!("Can not load rtt result cache cache: {err}");
-                    return Err(err);
-                }
+                error!("Can not load rtt result from {merge_file}: {err}");
+                return Err(err);
             }
+        };
+        info!(
+            "Merge {} rtt results from {merge_file}",
+            merge_rtt_results.len()
+        );
+        rtt_results.merge(merge_rtt_results);
+        rtt_results.commit();
+    }
+    if !blacklisted_subnets.is_empty() {
+        let blacklisted_ips: Vec<IpInet> = rtt_results
+            .iter()
+            .map(|(ip_inet, _)| *ip_inet)
+            .filter(|ip_inet| {
+                blacklisted_subnets
+                    .iter()
+                    .any(|blacklisted_subnet| blacklisted_subnet.contains(ip_inet.address()))
+            })
+            .collect();
+        for ip_inet in &blacklisted_ips {
+            rtt_results.remove_result(ip_inet);
         }
+        info!(
+            "Remove {} blacklisted ips from rtt results",
+            blacklisted_ips.len()
+        );
+    }
+    limit_rtt_results(&mut rtt_results, &args);
+    // only back up the result of the last run, later saves overwrite the result directly
+    if args.keep_backup {
+        rtt_results
+            .with_format(args.output_format)
+            .save_backup_async(&rtt_result_file_name)
+            .await?;
+    } else {
+        rtt_results
+            .with_format(args.output_format)
+            .save_async(&rtt_result_file_name)
+            .await?;
     }
-    rtt_results.save(&rtt_result_file_name)?;
-    rtt_result_cache.save(&rtt_result_cache_file_name)?;
+    // time spent before the tests, e.g. loading files, is also counted
+    let mut last_touch = Instant::now();
+    rtt_result_cache.touch(&mut last_touch);
+    rtt_result_cache
+        .save_async(&rtt_result_cache_file_name)
+        .await?;
 
     rtt_results.enable_subnets(subnets);
+    for (subnet_idx, subnet) in subnets.iter_mut().enumerate() {
+        if rtt_result_cache.is_subnet_tripped(subnet_idx, args.circuit_breaker_threshold) {
+            subnet.enable = false;
+        }
+    }
 
     fn calc_subnet_len(
         subnet: &Subnet,
@@ -390,7 +1765,7 @@ async fn main() -> Result<()> {
     }
 
     let mut all_ip_count = subnets.iter().fold(0, |acc, subnet| {
-        acc + calc_subnet_len(subnet, &rtt_result_cache, &args, max_subnet_len)
+        acc + calc_subnet_len(subnet, &rtt_result_cache, &args, max_subnet_len).div_ceil(density)
     });
 
     fn calc_start_ip_count(
@@ -398,67 +1773,163 @@ async fn main() -> Result<()> {
         rtt_result_cache: &RttResultCache,
         args: &Args,
         max_subnet_len: usize,
+        density: usize,
+        ips_per_subnet: usize,
     ) -> usize {
         subnets.iter().enumerate().fold(0, |acc, (i, subnet)| {
             let subnet_len = calc_subnet_len(subnet, rtt_result_cache, args, max_subnet_len);
-            acc + subnet_len.min(rtt_result_cache.current_subnet_start)
-                + if i < rtt_result_cache.current_subnet && subnet_len != 0 {
-                    1
-                } else {
-                    0
-                }
+            // the subnets before current_subnet are visited in the current pass
+            let tested_len = if i < rtt_result_cache.current_subnet {
+                rtt_result_cache.current_subnet_start + ips_per_subnet
+            } else {
+                rtt_result_cache.current_subnet_start
+            };
+            acc + subnet_len.min(tested_len).div_ceil(density)
         })
     }
 
-    let mut start_ip_count = calc_start_ip_count(subnets, &rtt_result_cache, &args, max_subnet_len);
+    let mut start_ip_count = calc_start_ip_count(
+        subnets,
+        &rtt_result_cache,
+        &args,
+        max_subnet_len,
+        density,
+        ips_per_subnet,
+    );
 
     info!("current progress: {start_ip_count}/{all_ip_count}");
 
-    let progress_bar = ProgressBar::new(all_ip_count as u64);
+    let progress_bar = Progress::new(all_ip_count as u64, args.progress_mode());
+    progress_bar.emit(&ProgressEvent::Start {
+        subnets: subnets.len(),
+        total_ips: all_ip_count,
+    });
+    let batch_timer = BatchTimer::default();
     progress_bar.set_style(
         ProgressStyle::with_template(
-            "{spinner:.green} [{wide_bar:.cyan/blue}] [{pos:>7}/{len:7}] {percent}% ({elapsed_precise}/{duration_precise})",
+            "{spinner:.green} [{wide_bar:.cyan/blue}] [{pos:>7}/{len:7}] {percent}% ({elapsed_precise}/eta {rolling_eta})",
         )
             .unwrap()
+            .with_key("rolling_eta", batch_timer.eta_tracker())
             .progress_chars("#>-"),
     );
     progress_bar.set_position(start_ip_count as u64);
     progress_bar.reset_eta();
 
+    // the first ctrl-c finishes the in-flight batch, the second one exits immediately
+    let interrupted = Arc::new(AtomicBool::new(false));
+    {
+        let interrupted = interrupted.clone();
+        let progress_bar = progress_bar.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                interrupted.store(true, Ordering::SeqCst);
+                progress_bar.println("Interrupted, waiting for the current batch to finish");
+            }
+            if tokio::signal::ctrl_c().await.is_ok() {
+                // process::exit skips the Drop of the sing boxes
+                terminate_all();
+                std::process::exit(130);
+            }
+        });
+    }
+
+    let sing_boxes = new_sing_boxes(&config);
+    let rate_limiter = new_rate_limiter(&args, &config);
+    let mut total_success = 0;
+    let mut total_tested = 0;
+    // the cache is saved every --checkpoint-every batches
+    let mut batch_since_checkpoint = 0;
+    let start_time = Instant::now();
+    // subnet_idx -> (success_count, total_count)
+    let mut subnet_stats: HashMap<usize, (usize, usize)> = HashMap::new();
     while rtt_result_cache.current_subnet_start < max_subnet_len {
+        if interrupted.load(Ordering::SeqCst) {
+            rtt_results.commit();
+            limit_rtt_results(&mut rtt_results, &args);
+            rtt_results
+                .with_format(args.output_format)
+                .save_async(&rtt_result_file_name)
+                .await?;
+            rtt_result_cache.touch(&mut last_touch);
+            rtt_result_cache
+                .save_async(&rtt_result_cache_file_name)
+                .await?;
+            progress_bar.abandon();
+            println!("Interrupted — progress saved");
+            return Ok(());
+        }
         let mut ips: Vec<IpInet> = Vec::new();
         let mut subnet_idxs: Vec<usize> = Vec::new();
         while ips.len() < config.max_connection_count {
+            // every ip of a subnet visit must fit in the batch
+            if config.max_connection_count - ips.len() < ips_per_subnet {
+                break;
+            }
             let subnet = &subnets[rtt_result_cache.current_subnet];
-            if !args.auto_skip
-                || rtt_result_cache.current_subnet_start < args.enable_threshold
-                || subnet.enable
-            {
-                if let Some(ip_inet) = subnet.get_ip(rtt_result_cache.current_subnet_start) {
-                    ips.push(ip_inet);
-                    subnet_idxs.push(rtt_result_cache.current_subnet);
+            let subnet_start = rtt_result_cache.current_subnet_start;
+            for ip_idx in subnet_start..(subnet_start + ips_per_subnet).min(max_subnet_len) {
+                if ip_idx % density != 0
+                    || (args.auto_skip && ip_idx >= args.enable_threshold && !subnet.enable)
+                {
+                    continue;
+                }
+                if rtt_result_cache.is_subnet_tripped(
+                    rtt_result_cache.current_subnet,
+                    args.circuit_breaker_threshold,
+                ) {
+                    progress_bar.inc(1);
+                    continue;
+                }
+                let ip_inet = if !args.random_order {
+                    subnet.get_ip(subnet.strided_index(ip_idx, stride))
+                } else if ip_idx < subnet.len() {
+                    Some(subnet.random_ip(&mut random_ip_rng(
+                        seed,
+                        rtt_result_cache.current_subnet,
+                        ip_idx,
+                    )))
+                } else {
+                    None
+                };
+                if let Some(ip_inet) = ip_inet {
+                    if excluded_subnets
+                        .iter()
+                        .chain(&blacklisted_subnets)
+                        .chain(&bogon_subnets)
+                        .any(|excluded_subnet| excluded_subnet.contains(ip_inet.address()))
+                    {
+                        progress_bar.inc(1);
+                    } else {
+                        ips.push(ip_inet);
+                        subnet_idxs.push(rtt_result_cache.current_subnet);
+                    }
                 }
             }
 
             rtt_result_cache.current_subnet += 1;
             if rtt_result_cache.current_subnet == subnets.len() {
                 rtt_result_cache.current_subnet = 0;
-                rtt_result_cache.current_subnet_start += 1;
+                rtt_result_cache.current_subnet_start =
+                    (subnet_start + ips_per_subnet).min(max_subnet_len);
 
-                if args.auto_skip && rtt_result_cache.current_subnet_start == args.enable_threshold
+                if args.auto_skip
+                    && subnet_start < args.enable_threshold
+                    && rtt_result_cache.current_subnet_start >= args.enable_threshold
                 {
                     all_ip_count = subnets.iter().fold(0, |acc, subnet| {
                         acc + calc_subnet_len(subnet, &rtt_result_cache, &args, max_subnet_len)
+                            .div_ceil(density)
                     });
 
 
                     // TODO:  可能会溢出，有空看看
                     // start_ip_count =
-                    //     calc_start_ip_count(subnets, &rtt_result_cache, &args, max_subnet_len)
+                    //     calc_start_ip_count(subnets, &rtt_result_cache, &args, max_subnet_len, density, ips_per_subnet)
                     //         - ips.len();
                     start_ip_count =
-                        if calc_start_ip_count(subnets, &rtt_result_cache, &args, max_subnet_len) >  ips.len() {
-                            calc_start_ip_count(subnets, &rtt_result_cache, &args, max_subnet_len) - ips.len()
+                        if calc_start_ip_count(subnets, &rtt_result_cache, &args, max_subnet_len, density, ips_per_subnet) >  ips.len() {
+                            calc_start_ip_count(subnets, &rtt_result_cache, &args, max_subnet_len, density, ips_per_subnet) - ips.len()
                 } else {
                             0
                         };
@@ -475,21 +1946,41 @@ async fn main() -> Result<()> {
             }
         }
 
+        let mut strict_warnings = Vec::new();
+        let batch_start = Instant::now();
         let test_res = test_rtts(
             &config,
+            &sing_boxes,
+            rate_limiter.as_ref(),
             &sing_box_template,
             &outbound_template,
             args.data_dir.as_str(),
             args.ignore_body_warning,
+            args.strict_mode,
+            args.fail_fast,
+            &mut strict_warnings,
             &progress_bar,
             &ips,
         )
         .await?;
+        batch_timer.record(batch_start.elapsed(), ips.len());
         let mut success_count = 0;
+        // subnet_idx -> whether any ip of the subnet succeeded in the batch
+        let mut batch_subnet_success: HashMap<usize, bool> = HashMap::new();
         for (i, ip) in ips.iter().enumerate() {
+            let subnet_stat = subnet_stats.entry(subnet_idxs[i]).or_insert((0, 0));
+            subnet_stat.1 += 1;
+            let subnet_success = batch_subnet_success.entry(subnet_idxs[i]).or_insert(false);
             if let Some(rtt) = &test_res[i] {
+                subnet_stat.0 += 1;
+                *subnet_success = true;
                 success_count += 1;
-                rtt_results.add_result(*ip, rtt.clone());
+                // each scan of watch mode only improves the results
+                if args.watch {
+                    rtt_results.add_better_result(*ip, rtt.clone());
+                } else {
+                    rtt_results.add_result(*ip, rtt.clone());
+                }
                 if rtt_result_cache.current_subnet_start < args.enable_threshold {
                     subnets[subnet_idxs[i]].enable = true;
                 }
@@ -498,7 +1989,11 @@ async fn main() -> Result<()> {
 
         if success_count != 0 {
             rtt_results.commit();
-            rtt_results.save(&rtt_result_file_name)?;
+            limit_rtt_results(&mut rtt_results, &args);
+            rtt_results
+                .with_format(args.output_format)
+                .save_async(&rtt_result_file_name)
+                .await?;
         }
 
         let log_str = format!(
@@ -511,10 +2006,182 @@ async fn main() -> Result<()> {
         );
         progress_bar.inc(ips.len() as u64);
         progress_bar.println(log_str.as_str());
+        let remaining = progress_bar
+            .length()
+            .unwrap_or_default()
+            .saturating_sub(progress_bar.position());
+        progress_bar.emit_progress(
+            batch_timer.eta(remaining).unwrap_or_default(),
+            success_count,
+            ips.len(),
+        );
         debug!("{log_str}");
-        rtt_result_cache.save(&rtt_result_cache_file_name)?
+        let mut batch_subnet_idxs: Vec<usize> = batch_subnet_success.keys().copied().collect();
+        batch_subnet_idxs.sort_unstable();
+        for subnet_idx in batch_subnet_idxs {
+            debug!(
+                "{}",
+                format_subnet_stat(&subnets[subnet_idx], subnet_stats[&subnet_idx])
+            );
+            let failure_count = rtt_result_cache
+                .record_subnet_batch(subnet_idx, !batch_subnet_success[&subnet_idx]);
+            if failure_count == args.circuit_breaker_threshold + 1 {
+                subnets[subnet_idx].enable = false;
+                warn!(
+                    "Skip subnet {} as it failed in {failure_count} batches in a row",
+                    subnets[subnet_idx].cidr
+                );
+            }
+        }
+        total_success += success_count;
+        total_tested += ips.len();
+
+        let strict_violation = if args.strict_mode {
+            config.strict_violation(strict_warnings.len(), ips.len(), success_count)
+        } else {
+            None
+        };
+        let abort_reason = too_many_errors(&args, total_tested, total_success);
+        batch_since_checkpoint += 1;
+        // always checkpoint before aborting, so the run can be resumed
+        if batch_since_checkpoint >= args.checkpoint_every
+            || abort_reason.is_some()
+            || strict_violation.is_some()
+        {
+            rtt_result_cache.touch(&mut last_touch);
+            rtt_result_cache
+                .save_async(&rtt_result_cache_file_name)
+                .await?;
+            batch_since_checkpoint = 0;
+        }
+
+        // results of the batch are saved above
+        if let Some(strict_violation) = strict_violation {
+            for warning in &strict_warnings {
+                error!("{warning}");
+            }
+            Err(ErrorKind::strict(strict_violation))?
+        }
+        if let Some(abort_reason) = abort_reason {
+            error!("Aborting: too many errors, {abort_reason}");
+            Err(ErrorKind::aborted(abort_reason))?
+        }
+
+        // start the next scan from the beginning in watch mode
+        if args.watch
+            && rtt_result_cache.current_subnet_start >= max_subnet_len
+            && !interrupted.load(Ordering::SeqCst)
+        {
+            rtt_results.commit();
+            limit_rtt_results(&mut rtt_results, &args);
+            if args.group_by_subnet {
+                write_atomic_async(
+                    &rtt_result_file_name,
+                    rtt_results.to_grouped_string(subnets)?.as_bytes(),
+                )
+                .await?;
+            } else {
+                rtt_results
+                    .with_format(args.output_format)
+                    .save_async(&rtt_result_file_name)
+                    .await?;
+            }
+            info!(
+                "Watch mode: sleeping {}s before next scan",
+                args.watch_interval_secs
+            );
+            // ctrl-c during the sleep finishes the run with the completed scan
+            let deadline = Instant::now() + Duration::from_secs(args.watch_interval_secs);
+            while !interrupted.load(Ordering::SeqCst) && Instant::now() < deadline {
+                tokio::time::sleep(
+                    deadline
+                        .saturating_duration_since(Instant::now())
+                        .min(Duration::from_secs(1)),
+                )
+                .await;
+            }
+            // the sleep is not test time
+            last_touch = Instant::now();
+            if interrupted.load(Ordering::SeqCst) {
+                break;
+            }
+
+            rtt_result_cache.current_subnet = 0;
+            rtt_result_cache.current_subnet_start = 0;
+            rtt_result_cache.cache_created_at = unix_timestamp();
+            rtt_result_cache.started_at = 0;
+            rtt_result_cache.elapsed_secs = 0;
+            // subnets skipped by the circuit breaker are tested again in every scan
+            rtt_result_cache.subnet_failure_counts.clear();
+            rtt_result_cache.run_id += 1;
+            if let Some(output_file_template) = &args.output_file_template {
+                rtt_result_file_name =
+                    resolve_output_template(output_file_template, &rtt_result_cache);
+                info!(
+                    "Write rtt results of run {} to {rtt_result_file_name}",
+                    rtt_result_cache.run_id
+                );
+            }
+            rtt_result_cache.touch(&mut last_touch);
+            rtt_result_cache
+                .save_async(&rtt_result_cache_file_name)
+                .await?;
+            all_ip_count = subnets.iter().fold(0, |acc, subnet| {
+                acc + calc_subnet_len(subnet, &rtt_result_cache, &args, max_subnet_len)
+                    .div_ceil(density)
+            });
+            progress_bar.set_length(all_ip_count as u64);
+            progress_bar.set_position(0);
+            progress_bar.reset_eta();
+        }
+    }
+    rtt_result_cache.touch(&mut last_touch);
+    rtt_result_cache
+        .save_async(&rtt_result_cache_file_name)
+        .await?;
+    info!(
+        "Run started at {} finished, ran for {}s across all sessions",
+        rtt_result_cache.started_at, rtt_result_cache.elapsed_secs
+    );
+    // results are saved as a flat list during the run, group them once at the end
+    if args.group_by_subnet {
+        write_atomic_async(
+            &rtt_result_file_name,
+            rtt_results.to_grouped_string(subnets)?.as_bytes(),
+        )
+        .await?;
     }
 
+    progress_bar.emit(&ProgressEvent::Done { total_success });
     progress_bar.finish_with_message("finish!");
+    log_subnet_scores(subnets, &rtt_results);
+    if let Some(metrics_output) = &args.metrics_output {
+        write_prometheus(&rtt_results, metrics_output, total_tested, total_success)?;
+        info!("Metrics written to {metrics_output}");
+    }
+    // only the top table is printed with --quiet
+    if !args.quiet {
+        if !args.no_summary {
+            print!("{}", percentiles(&rtt_results));
+        }
+        print_rtt_histogram(&rtt_results, args.histogram_buckets);
+        if args.subnet_report {
+            print_subnet_report(subnets, &subnet_stats);
+        }
+        if args.coverage_report {
+            print_coverage_report(subnets, &rtt_results, max_subnet_len);
+        }
+    }
+    print_top_table(&rtt_results, args.top);
+    if let Some(webhook_url) = &config.webhook_url {
+        send_webhook(
+            webhook_url,
+            &rtt_results,
+            total_tested,
+            total_success,
+            start_time.elapsed(),
+        )
+        .await;
+    }
     Ok(())
 }
@@ -0,0 +1,55 @@
+use std::fmt::Write;
+
+use crate::cache::RttResults;
+use crate::data::write_atomic;
+use crate::error::Result;
+
+/// Render `results` and the counts of a run in the Prometheus text format.
+pub fn to_prometheus(results: &RttResults, total_tested: usize, total_success: usize) -> String {
+    let mut ret = String::new();
+    // writing to a String never fails
+    let _ = writeln!(
+        ret,
+        "# HELP cdntest_ip_server_rtt_ms Server rtt of the ip in ms."
+    );
+    let _ = writeln!(ret, "# TYPE cdntest_ip_server_rtt_ms gauge");
+    for (ip_inet, rtt_result) in results.iter() {
+        let _ = writeln!(
+            ret,
+            "cdntest_ip_server_rtt_ms{{ip=\"{ip_inet}\"}} {}",
+            rtt_result.server_rtt()
+        );
+    }
+    let _ = writeln!(ret, "# HELP cdntest_ip_cdn_rtt_ms Cdn rtt of the ip in ms.");
+    let _ = writeln!(ret, "# TYPE cdntest_ip_cdn_rtt_ms gauge");
+    for (ip_inet, rtt_result) in results.iter() {
+        let _ = writeln!(
+            ret,
+            "cdntest_ip_cdn_rtt_ms{{ip=\"{ip_inet}\"}} {}",
+            rtt_result.cdn_rtt()
+        );
+    }
+    let _ = writeln!(ret, "# HELP cdntest_total_tested Ips tested in the run.");
+    let _ = writeln!(ret, "# TYPE cdntest_total_tested gauge");
+    let _ = writeln!(ret, "cdntest_total_tested {total_tested}");
+    let _ = writeln!(
+        ret,
+        "# HELP cdntest_total_success Ips tested successfully in the run."
+    );
+    let _ = writeln!(ret, "# TYPE cdntest_total_success gauge");
+    let _ = writeln!(ret, "cdntest_total_success {total_success}");
+    ret
+}
+
+/// Write [`to_prometheus`] to `path`, e.g. for the textfile collector of node exporter.
+pub fn write_prometheus(
+    results: &RttResults,
+    path: &str,
+    total_tested: usize,
+    total_success: usize,
+) -> Result<()> {
+    write_atomic(
+        path,
+        to_prometheus(results, total_tested, total_success).as_bytes(),
+    )
+}
@@ -0,0 +1,96 @@
+use std::ops::Deref;
+use std::time::Duration;
+
+use indicatif::{ProgressBar, ProgressDrawTarget};
+use serde::Serialize;
+
+/// Json lines written to stderr by [`Progress`] with `--progress-json`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    Start {
+        subnets: usize,
+        total_ips: usize,
+    },
+    Progress {
+        done: u64,
+        total: u64,
+        eta_secs: u64,
+        last_batch_success: usize,
+        last_batch_total: usize,
+    },
+    Log {
+        msg: String,
+    },
+    Done {
+        total_success: usize,
+    },
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressMode {
+    #[default]
+    Bar,
+    /// Json lines on stderr, see [`ProgressEvent`]
+    Json,
+    /// Nothing is printed
+    Quiet,
+}
+
+/// A progress bar which reports json lines or nothing instead of drawing, see [`ProgressMode`].
+///
+/// Derefs to the underlying [`ProgressBar`], which is hidden unless in [`ProgressMode::Bar`] but
+/// still tracks the position and eta.
+#[derive(Clone)]
+pub struct Progress {
+    progress_bar: ProgressBar,
+    mode: ProgressMode,
+}
+
+impl Progress {
+    pub fn new(len: u64, mode: ProgressMode) -> Self {
+        let progress_bar = if mode == ProgressMode::Bar {
+            ProgressBar::new(len)
+        } else {
+            ProgressBar::with_draw_target(Some(len), ProgressDrawTarget::hidden())
+        };
+        Self { progress_bar, mode }
+    }
+
+    /// Only emitted in json mode.
+    pub fn emit(&self, event: &ProgressEvent) {
+        if self.mode == ProgressMode::Json {
+            eprintln!("{}", serde_json::to_string(event).unwrap());
+        }
+    }
+
+    pub fn println<I: AsRef<str>>(&self, msg: I) {
+        match self.mode {
+            ProgressMode::Bar => self.progress_bar.println(msg),
+            ProgressMode::Json => self.emit(&ProgressEvent::Log {
+                msg: msg.as_ref().into(),
+            }),
+            ProgressMode::Quiet => {}
+        }
+    }
+
+    /// Emit a [`ProgressEvent::Progress`] of the current position, `eta` is passed in so that
+    /// it matches the eta shown by the progress bar.
+    pub fn emit_progress(&self, eta: Duration, last_batch_success: usize, last_batch_total: usize) {
+        self.emit(&ProgressEvent::Progress {
+            done: self.progress_bar.position(),
+            total: self.progress_bar.length().unwrap_or_default(),
+            eta_secs: eta.as_secs(),
+            last_batch_success,
+            last_batch_total,
+        });
+    }
+}
+
+impl Deref for Progress {
+    type Target = ProgressBar;
+
+    fn deref(&self) -> &Self::Target {
+        &self.progress_bar
+    }
+}
@@ -0,0 +1,110 @@
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use governor::DefaultDirectRateLimiter;
+use reqwest::header::HeaderMap;
+use reqwest::{Client, Method, Url};
+
+use crate::config::BodyMatcher;
+use crate::error::ReqwestError;
+
+/// Upper bound of the wait before a retry.
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Wait before the `attempt`-th retry, `retry_delay_ms * 2^attempt` capped at [`MAX_RETRY_DELAY`].
+pub fn retry_delay(retry_delay_ms: u64, attempt: u32) -> Duration {
+    let delay_ms = 2_u64
+        .checked_pow(attempt)
+        .map_or(u64::MAX, |factor| retry_delay_ms.saturating_mul(factor));
+    Duration::from_millis(delay_ms).min(MAX_RETRY_DELAY)
+}
+
+/// How a response is checked and how the request is retried and sampled.
+#[derive(Debug, Clone)]
+pub struct RttTestOptions {
+    pub body_matcher: BodyMatcher,
+    pub expected_status: RangeInclusive<u16>,
+    /// Only network errors are retried, see [`retry_delay`].
+    pub retry_count: u32,
+    pub retry_delay_ms: u64,
+    pub rtt_samples: usize,
+}
+
+pub async fn do_test_rtt_once(
+    client: &Client,
+    method: Method,
+    url: Url,
+    headers: &HeaderMap,
+    options: &RttTestOptions,
+    rate_limiter: Option<&DefaultDirectRateLimiter>,
+) -> core::result::Result<u64, ReqwestError> {
+    // wait before the timer starts, the wait is not part of the rtt
+    if let Some(rate_limiter) = rate_limiter {
+        rate_limiter.until_ready().await;
+    }
+    let start = SystemTime::now();
+    let res = client
+        .request(method.clone(), url.clone())
+        .headers(headers.clone())
+        .send()
+        .await
+        .map_err(ReqwestError::network)?;
+
+    if !options.expected_status.contains(&res.status().as_u16()) {
+        Err(ReqwestError::status_error(
+            res.status().as_u16(),
+            url.to_string(),
+        ))?
+    }
+    // HEAD responses have no body, only check the status
+    if method == Method::HEAD {
+        return Ok(SystemTime::now().duration_since(start).unwrap().as_millis() as u64);
+    }
+    let body = res.text().await.map_err(ReqwestError::network)?;
+    if !options.body_matcher.is_match(&body) {
+        Err(ReqwestError::body_no_match(
+            body,
+            options.body_matcher.to_string(),
+        ))?
+    }
+    Ok(SystemTime::now().duration_since(start).unwrap().as_millis() as u64)
+}
+
+/// Test `rtt_samples` times sequentially, a failed sample fails the whole test.
+pub async fn do_test_rtt(
+    client: Client,
+    method: Method,
+    url: Url,
+    headers: HeaderMap,
+    options: RttTestOptions,
+    rate_limiter: Option<Arc<DefaultDirectRateLimiter>>,
+) -> core::result::Result<Vec<u64>, ReqwestError> {
+    let mut rtts = Vec::new();
+    for _ in 0..options.rtt_samples.max(1) {
+        let mut attempt = 0;
+        loop {
+            match do_test_rtt_once(
+                &client,
+                method.clone(),
+                url.clone(),
+                &headers,
+                &options,
+                rate_limiter.as_deref(),
+            )
+            .await
+            {
+                // body unmatched and status error are deterministic, only retry network error
+                Err(ReqwestError::Network { .. }) if attempt < options.retry_count => {
+                    tokio::time::sleep(retry_delay(options.retry_delay_ms, attempt)).await;
+                    attempt += 1;
+                }
+                res => {
+                    rtts.push(res?);
+                    break;
+                }
+            }
+        }
+    }
+    Ok(rtts)
+}
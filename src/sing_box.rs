@@ -0,0 +1,288 @@
+use std::collections::VecDeque;
+use std::io;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, error, warn};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::runtime::Handle;
+
+use crate::data::Savable;
+use crate::error::{ErrorKind, Result};
+use crate::template::SingBoxConfig;
+
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+/// Number of the last stderr lines logged when sing box exits unexpectedly.
+const STDERR_TAIL_LINES: usize = 20;
+
+/// Pids of the running sing box processes, see [`terminate_all`].
+static RUNNING_PIDS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// Send SIGTERM to every running sing box before exiting without dropping them, sing box runs
+/// in its own process group and does not receive the ctrl-c of the terminal.
+#[cfg(unix)]
+pub fn terminate_all() {
+    for pid in RUNNING_PIDS.lock().unwrap().drain(..) {
+        // SAFETY: pid is a child process which has not been reaped yet, pids are removed
+        // from RUNNING_PIDS before they are reaped
+        unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+    }
+}
+
+/// sing box shares the console of the terminal and receives ctrl-c by itself.
+#[cfg(not(unix))]
+pub fn terminate_all() {}
+
+fn unregister_pid(pid: Option<u32>) {
+    RUNNING_PIDS
+        .lock()
+        .unwrap()
+        .retain(|running_pid| Some(*running_pid) != pid);
+}
+
+pub struct SingBox {
+    child: Child,
+    /// Pid registered in [`RUNNING_PIDS`], kept as `Child::id` is `None` once reaped.
+    pid: Option<u32>,
+    /// Set by [`SingBox::shutdown`], stderr is closed as expected after it.
+    stopping: Arc<AtomicBool>,
+    /// Set when stderr is closed while sing box is not being shut down.
+    crashed: Arc<AtomicBool>,
+}
+
+impl SingBox {
+    pub async fn new(
+        sing_box_path: &str,
+        config_file_name: &str,
+        extra_args: &[String],
+        startup_timeout: Duration,
+    ) -> Result<Self> {
+        let mut command = Command::new(sing_box_path);
+        command
+            .args(["run", "-c", config_file_name])
+            .args(extra_args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        // keep sing box out of the terminal's process group, so ctrl-c only reaches us and the
+        // in-flight batch can finish
+        #[cfg(unix)]
+        command.process_group(0);
+        debug!("Start sing box: {:?}", command.as_std());
+        let mut child = command.spawn().map_err(ErrorKind::process)?;
+        let mut tmp_buf = [0_u8];
+        match tokio::time::timeout(
+            startup_timeout,
+            child.stderr.as_mut().unwrap().read_exact(&mut tmp_buf),
+        )
+        .await
+        {
+            Ok(Ok(_)) => {}
+            Ok(Err(read_stdout_err)) => {
+                child.wait().await.map_err(ErrorKind::process)?;
+                let mut stderr_output = Vec::new();
+                child
+                    .stderr
+                    .as_mut()
+                    .unwrap()
+                    .read_to_end(&mut stderr_output)
+                    .await
+                    .map_err(ErrorKind::process)?;
+                let stderr_output_str = String::from_utf8(stderr_output.clone()).unwrap();
+                error!("{read_stdout_err}\noutput: \n{stderr_output_str}");
+                Err(ErrorKind::process(read_stdout_err))?
+            }
+            Err(_) => {
+                // sing box hangs without any output
+                child.kill().await.map_err(ErrorKind::process)?;
+                let mut stderr_output = Vec::new();
+                child
+                    .stderr
+                    .as_mut()
+                    .unwrap()
+                    .read_to_end(&mut stderr_output)
+                    .await
+                    .map_err(ErrorKind::process)?;
+                let timeout_err = io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "sing box did not start in {startup_timeout:?}, output: \n{}",
+                        String::from_utf8_lossy(&stderr_output)
+                    ),
+                );
+                error!("{timeout_err}");
+                Err(ErrorKind::process(timeout_err))?
+            }
+        }
+        let stopping = Arc::new(AtomicBool::new(false));
+        let crashed = Arc::new(AtomicBool::new(false));
+        // the byte read above is the start of the first line
+        let stderr = io::Cursor::new(tmp_buf).chain(child.stderr.take().unwrap());
+        tokio::spawn(forward_stderr(stderr, stopping.clone(), crashed.clone()));
+        let pid = child.id();
+        RUNNING_PIDS.lock().unwrap().extend(pid);
+        Ok(Self {
+            child,
+            pid,
+            stopping,
+            crashed,
+        })
+    }
+
+    /// Whether sing box exited without [`SingBox::shutdown`].
+    pub fn crashed(&self) -> bool {
+        self.crashed.load(Ordering::SeqCst)
+    }
+
+    /// Whether the process has not exited, `&mut` as it may reap the exited process.
+    pub fn is_alive(&mut self) -> bool {
+        // hold the lock while reaping, so terminate_all never signals a reused pid
+        let mut running_pids = RUNNING_PIDS.lock().unwrap();
+        let alive = matches!(self.child.try_wait(), Ok(None));
+        if !alive {
+            running_pids.retain(|running_pid| Some(*running_pid) != self.pid);
+        }
+        alive
+    }
+
+    /// Ask sing box to exit gracefully so that it releases its ports, kill it if it does not
+    /// exit in [`SHUTDOWN_TIMEOUT`].
+    pub async fn shutdown(&mut self) -> Result<()> {
+        self.stopping.store(true, Ordering::SeqCst);
+        unregister_pid(self.pid);
+        if self
+            .child
+            .try_wait()
+            .map_err(ErrorKind::process)?
+            .is_some()
+        {
+            return Ok(());
+        }
+        self.terminate()?;
+        tokio::select! {
+            res = self.child.wait() => {
+                res.map_err(ErrorKind::process)?;
+            }
+            _ = tokio::time::sleep(SHUTDOWN_TIMEOUT) => {
+                warn!("sing box did not exit in {SHUTDOWN_TIMEOUT:?}, kill it");
+                self.child.kill().await.map_err(ErrorKind::process)?;
+            }
+        }
+        Ok(())
+    }
+
+    // tokio `Child::start_kill` sends SIGKILL, so send SIGTERM by ourselves
+    #[cfg(unix)]
+    fn terminate(&mut self) -> Result<()> {
+        if let Some(pid) = self.child.id() {
+            // SAFETY: pid is our child process which has not been reaped yet
+            if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+                Err(ErrorKind::process(io::Error::last_os_error()))?
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn terminate(&mut self) -> Result<()> {
+        Ok(self.child.start_kill().map_err(ErrorKind::process)?)
+    }
+}
+
+/// Forward the stderr of sing box to the debug log until sing box exits.
+async fn forward_stderr<R: AsyncRead + Unpin>(
+    stderr: R,
+    stopping: Arc<AtomicBool>,
+    crashed: Arc<AtomicBool>,
+) {
+    let mut lines = BufReader::new(stderr).lines();
+    let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+    while let Ok(Some(line)) = lines.next_line().await {
+        debug!("sing box: {line}");
+        if tail.len() == STDERR_TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(line);
+    }
+    if !stopping.load(Ordering::SeqCst) {
+        crashed.store(true, Ordering::SeqCst);
+        error!(
+            "sing box exited unexpectedly, output: \n{}",
+            Vec::from(tail).join("\n")
+        );
+    }
+}
+
+impl Drop for SingBox {
+    fn drop(&mut self) {
+        tokio::task::block_in_place(move || {
+            Handle::current().block_on(async {
+                if let Err(err) = self.shutdown().await {
+                    error!("sing box shutdown failed: {err}");
+                } else {
+                    debug!("child shutdown!");
+                }
+            });
+        });
+    }
+}
+
+/// Keep one sing box process across batches, it is only restarted when the config changes.
+pub struct ReusableSingBox {
+    sing_box_path: String,
+    extra_args: Vec<String>,
+    startup_timeout: Duration,
+    sing_box: Option<SingBox>,
+    checksum: Option<u64>,
+}
+
+impl ReusableSingBox {
+    pub fn new(sing_box_path: &str, extra_args: &[String], startup_timeout: Duration) -> Self {
+        Self {
+            sing_box_path: sing_box_path.into(),
+            extra_args: extra_args.to_vec(),
+            startup_timeout,
+            sing_box: None,
+            checksum: None,
+        }
+    }
+
+    /// Write `config` to `config_file_name` and restart sing box if `config` changed since the
+    /// last update, return whether sing box was restarted.
+    pub async fn update(&mut self, config: &SingBoxConfig, config_file_name: &str) -> Result<bool> {
+        let checksum = config.checksum();
+        if self.is_alive() && !self.crashed() && self.checksum == Some(checksum) {
+            debug!("sing box config is unchanged, reuse the running sing box");
+            return Ok(false);
+        }
+        self.checksum = None;
+        if let Some(mut sing_box) = self.sing_box.take() {
+            // the old process must release its ports before the new one binds them
+            sing_box.shutdown().await?;
+        }
+        config.save_async(config_file_name).await?;
+        self.sing_box = Some(
+            SingBox::new(
+                &self.sing_box_path,
+                config_file_name,
+                &self.extra_args,
+                self.startup_timeout,
+            )
+            .await?,
+        );
+        self.checksum = Some(checksum);
+        Ok(true)
+    }
+
+    /// Whether the running sing box exited without being shut down.
+    pub fn crashed(&self) -> bool {
+        self.sing_box.as_ref().is_some_and(SingBox::crashed)
+    }
+
+    /// Whether sing box is started and has not exited.
+    pub fn is_alive(&mut self) -> bool {
+        self.sing_box.as_mut().is_some_and(SingBox::is_alive)
+    }
+}
@@ -1,12 +1,32 @@
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
 
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 
-use cdn_ip_tester_derive::{JsonLoadable, JsonSavable};
+use cdn_ip_tester_derive::{JsonLoadable, JsonSavablePretty};
 
-#[derive(Serialize, Deserialize, Clone, Debug, JsonLoadable, JsonSavable)]
+use crate::error::{DeserializedError, Result};
+
+/// Outbound types of sing-box which can be used in the outbound template.
+pub const KNOWN_OUTBOUND_TYPES: &[&str] = &[
+    "socks",
+    "http",
+    "shadowsocks",
+    "vmess",
+    "trojan",
+    "wireguard",
+    "hysteria",
+    "hysteria2",
+    "shadowtls",
+    "vless",
+    "tuic",
+    "ssh",
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonLoadable, JsonSavablePretty)]
 pub struct SingBoxConfig {
     inbounds: Vec<Inbound>,
     outbounds: Vec<Outbound>,
@@ -38,6 +58,81 @@ impl SingBoxConfig {
         }
         ret
     }
+
+    /// Check that tags are unique and every rule references existing tags.
+    pub fn validate(&self) -> Result<()> {
+        let mut inbound_tags = HashSet::new();
+        for tag in self
+            .inbounds
+            .iter()
+            .filter_map(|inbound| get_tag(&inbound.other))
+        {
+            if !inbound_tags.insert(tag) {
+                Err(DeserializedError::custom(
+                    format!("duplicated inbound tag {tag:?}").as_str(),
+                ))?
+            }
+        }
+        let mut outbound_tags = HashSet::new();
+        for tag in self
+            .outbounds
+            .iter()
+            .filter_map(|outbound| get_tag(&outbound.other))
+        {
+            if !outbound_tags.insert(tag) {
+                Err(DeserializedError::custom(
+                    format!("duplicated outbound tag {tag:?}").as_str(),
+                ))?
+            }
+        }
+        for rule in &self.route.rules {
+            if !outbound_tags.contains(rule.outbound.as_str()) {
+                Err(DeserializedError::custom(
+                    format!("rule references unknown outbound tag {:?}", rule.outbound).as_str(),
+                ))?
+            }
+            for inbound in rule.inbounds() {
+                if !inbound_tags.contains(inbound) {
+                    Err(DeserializedError::custom(
+                        format!("rule references unknown inbound tag {inbound:?}").as_str(),
+                    ))?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the `dns` section, replacing the one of the template.
+    pub fn with_dns_section(mut self, dns_value: Value) -> Self {
+        self.other.insert("dns".into(), dns_value);
+        self
+    }
+
+    /// Resolve every domain with `server_addr`, e.g. `8.8.8.8` or `tls://1.1.1.1`.
+    pub fn with_dns_server(self, server_addr: &str) -> Self {
+        self.with_dns_section(json!({
+            "servers": [{ "tag": "dns-server", "address": server_addr }],
+            "final": "dns-server",
+        }))
+    }
+
+    /// Same configs always have the same checksum, see the [`Hash`] impl.
+    pub fn checksum(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl Hash for SingBoxConfig {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // objects of serde_json::Value are sorted by key, the iteration order of HashMap is random
+        serde_json::to_value(self).unwrap().to_string().hash(state);
+    }
+}
+
+fn get_tag(other: &HashMap<String, Value>) -> Option<&str> {
+    other.get("tag").and_then(Value::as_str)
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -68,12 +163,73 @@ pub struct Outbound {
 }
 
 impl Outbound {
+    pub fn validate(&self) -> Result<()> {
+        self.validate_with_types(KNOWN_OUTBOUND_TYPES)
+    }
+
+    /// `server` may be absent, it is always overridden by [`Outbound::generate`].
+    pub fn validate_with_types(&self, known_types: &[&str]) -> Result<()> {
+        let outbound_type = self
+            .other
+            .get("type")
+            .ok_or_else(|| DeserializedError::custom("outbound template must have \"type\""))?;
+        if !outbound_type
+            .as_str()
+            .is_some_and(|outbound_type| known_types.contains(&outbound_type))
+        {
+            Err(DeserializedError::custom(
+                format!("outbound template type {outbound_type} is not one of {known_types:?}")
+                    .as_str(),
+            ))?
+        }
+        if let Some(server) = self.other.get("server") {
+            if !server.is_string() {
+                Err(DeserializedError::custom(
+                    format!("outbound template server {server} must be a string").as_str(),
+                ))?
+            }
+        }
+        Ok(())
+    }
+
     pub fn generate(&self, tag: String, server: String) -> Self {
         let mut ret = self.clone();
         ret.other.insert("tag".into(), tag.into());
         ret.other.insert("server".into(), server.into());
         ret
     }
+
+    /// Set the `tls` section, replacing the one of the template.
+    pub fn with_tls_config(mut self, server_name: &str, insecure: bool) -> Self {
+        self.other.insert(
+            "tls".into(),
+            json!({
+                "enabled": true,
+                "server_name": server_name,
+                "insecure": insecure,
+            }),
+        );
+        self
+    }
+
+    /// Set the `multiplex` section, replacing the one of the template.
+    pub fn with_multiplex(mut self, enabled: bool, max_streams: u32) -> Self {
+        self.other.insert(
+            "multiplex".into(),
+            json!({
+                "enabled": enabled,
+                "max_streams": max_streams,
+            }),
+        );
+        self
+    }
+
+    /// Set the `transport` section to a transport of `transport_type`, e.g. `ws` or `grpc`.
+    pub fn with_transport(mut self, transport_type: &str) -> Self {
+        self.other
+            .insert("transport".into(), json!({ "type": transport_type }));
+        self
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -99,4 +255,13 @@ impl Rule {
         ret.other.insert("inbound".into(), vec![inbound].into());
         ret
     }
+
+    /// `inbound` of a rule is either a tag or a list of tags.
+    fn inbounds(&self) -> Vec<&str> {
+        match self.other.get("inbound") {
+            Some(Value::String(inbound)) => vec![inbound.as_str()],
+            Some(Value::Array(inbounds)) => inbounds.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        }
+    }
 }
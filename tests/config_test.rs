@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+use cdn_ip_tester::cache::SortKey;
+use cdn_ip_tester::config::{Config, DEFAULT_CONFIG_TOML};
+use cdn_ip_tester::data::Savable;
+use cdn_ip_tester::error::ErrorKind;
+
+fn assert_config_error(config: Config) {
+    let err = config.validate().unwrap_err();
+    assert!(matches!(*err.0, ErrorKind::Config { .. }), "{err}");
+}
+
+#[test]
+fn config_validate() {
+    assert!(Config::default().validate().is_ok());
+
+    assert_config_error(Config {
+        server_url: "127.0.0.1".into(),
+        ..Default::default()
+    });
+    assert_config_error(Config {
+        cdn_url: "ftp://example.com".into(),
+        ..Default::default()
+    });
+    assert_config_error(Config {
+        port_base: 65500,
+        max_connection_count: 50,
+        ..Default::default()
+    });
+    assert_config_error(Config {
+        max_rtt: 0,
+        ..Default::default()
+    });
+    assert_config_error(Config {
+        connection_timeout_ms: Some(0),
+        ..Default::default()
+    });
+    assert_config_error(Config {
+        retry_count: 11,
+        ..Default::default()
+    });
+    assert_config_error(Config {
+        listen_ip: "localhost".into(),
+        ..Default::default()
+    });
+    assert_config_error(Config {
+        score_cdn_weight: -1.0,
+        ..Default::default()
+    });
+    assert_config_error(Config {
+        cdn_request_method: "POST".into(),
+        ..Default::default()
+    });
+    assert_config_error(Config {
+        cdn_expected_status_min: 300,
+        cdn_expected_status_max: 299,
+        ..Default::default()
+    });
+}
+
+#[test]
+fn config_env_overrides() {
+    let mut config = Config::default();
+    std::env::set_var("CDNTEST_MAX_RTT", "1234");
+    std::env::set_var("CDNTEST_CDN_URL", "http://example.com/");
+    config.apply_env_overrides().unwrap();
+    assert_eq!(1234, config.max_rtt);
+    assert_eq!("http://example.com/", config.cdn_url);
+
+    std::env::set_var("CDNTEST_PORT_BASE", "not a number");
+    assert!(config.apply_env_overrides().is_err());
+    std::env::remove_var("CDNTEST_PORT_BASE");
+    std::env::remove_var("CDNTEST_MAX_RTT");
+    std::env::remove_var("CDNTEST_CDN_URL");
+}
+
+#[test]
+fn config_default_toml() {
+    let default_config: toml::Table =
+        toml::from_str(&Config::default().to_string().unwrap()).unwrap();
+    let default_config_toml: toml::Table = toml::from_str(DEFAULT_CONFIG_TOML).unwrap();
+    assert_eq!(default_config, default_config_toml);
+}
+
+#[test]
+fn config_cdn_request_headers() {
+    let mut config = Config::default();
+    config
+        .cdn_request_headers
+        .insert("Host".into(), "example.com".into());
+    config
+        .cdn_request_headers
+        .insert("Authorization".into(), "Bearer secret".into());
+    assert!(config.validate().is_ok());
+    assert_eq!(2, config.cdn_header_map().unwrap().len());
+    assert_eq!(
+        vec![("Authorization", "<redacted>"), ("Host", "example.com")],
+        config.redacted_cdn_request_headers()
+    );
+
+    config
+        .cdn_request_headers
+        .insert("Bad Header".into(), "value".into());
+    assert_config_error(config);
+}
+
+#[test]
+fn config_body_matcher() {
+    let mut config = Config {
+        cdn_res_body: "error code: 10\\d+".into(),
+        ..Default::default()
+    };
+    let body_matcher = config.cdn_body_matcher().unwrap();
+    assert!(!body_matcher.is_match("error code: 1003"));
+
+    config.cdn_res_body_regex = true;
+    let body_matcher = config.cdn_body_matcher().unwrap();
+    assert!(body_matcher.is_match("error code: 1003"));
+    assert!(!body_matcher.is_match("error code: 2003"));
+    assert_eq!("regex error code: 10\\d+", body_matcher.to_string());
+
+    config.cdn_res_body = "(".into();
+    assert_config_error(config);
+}
+
+#[test]
+fn config_score_weights_ignored() {
+    assert!(!Config::default().score_weights_ignored(SortKey::ServerRtt));
+    let config = Config {
+        sort_by: SortKey::CdnRtt,
+        score_cdn_weight: 0.8,
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+    assert!(config.score_weights_ignored(config.sort_by));
+    assert!(config.score_weights_ignored(SortKey::ServerRtt));
+    assert!(!config.score_weights_ignored(SortKey::Score));
+}
+
+#[test]
+fn config_connection_timeout() {
+    let config = Config {
+        max_rtt: 800,
+        ..Default::default()
+    };
+    assert_eq!(Duration::from_millis(800), config.connection_timeout());
+    let config = Config {
+        connection_timeout_ms: Some(300),
+        ..config
+    };
+    assert_eq!(Duration::from_millis(300), config.connection_timeout());
+}
+
+#[test]
+fn config_strict_violation() {
+    let config = Config {
+        strict_max_failure_pct: 50.0,
+        ..Default::default()
+    };
+    assert_eq!(None, config.strict_violation(0, 10, 5));
+    assert_eq!(None, config.strict_violation(0, 0, 0));
+    // a body mismatch fails the batch even if every other ip succeeded
+    assert!(config
+        .strict_violation(1, 10, 9)
+        .unwrap()
+        .contains("body unmatched"));
+    assert!(config
+        .strict_violation(0, 10, 4)
+        .unwrap()
+        .contains("strict_max_failure_pct"));
+    // the default allows every ip to fail
+    assert_eq!(None, Config::default().strict_violation(0, 10, 0));
+}
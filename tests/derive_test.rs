@@ -0,0 +1,112 @@
+use serde::{Deserialize, Serialize};
+
+use cdn_ip_tester::data::{Loadable, Savable};
+use cdn_ip_tester_derive::{
+    BincodeLoadable, BincodeSavable, CsvSavable, JsonLoadable, JsonSavable, JsonSavablePretty,
+    YamlLoadable, YamlSavable,
+};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, YamlLoadable, YamlSavable)]
+struct YamlData {
+    name: String,
+    count: u64,
+    tags: Vec<String>,
+}
+
+#[test]
+fn yaml_round_trip() {
+    let data = YamlData {
+        name: "cdn".into(),
+        count: 3,
+        tags: vec!["a".into(), "b".into()],
+    };
+    let s = data.to_string().unwrap();
+    assert_eq!("name: cdn\ncount: 3\ntags:\n- a\n- b\n", s);
+    assert_eq!(data, YamlData::from_str(&s).unwrap());
+    assert!(YamlData::from_str("name: [").is_err());
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, BincodeLoadable, BincodeSavable)]
+struct BincodeData {
+    entries: Vec<(String, u64)>,
+}
+
+#[test]
+fn bincode_round_trip() {
+    let data = BincodeData {
+        entries: (0..10000).map(|i| (format!("10.0.{i}.0/24"), i)).collect(),
+    };
+    assert_eq!(
+        data,
+        BincodeData::from_str(&data.to_string().unwrap()).unwrap()
+    );
+    assert!(BincodeData::from_str("not hex").is_err());
+
+    let path = std::env::temp_dir().join("cdn-ip-tester-bincode-data.bin");
+    data.save(&path).unwrap();
+    assert_eq!(data.to_bytes().unwrap(), std::fs::read(&path).unwrap());
+    assert_eq!(data, BincodeData::load(&path).unwrap());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[derive(Serialize, JsonSavable)]
+struct CompactJsonData {
+    name: String,
+}
+
+#[derive(Serialize, JsonSavablePretty)]
+struct PrettyJsonData {
+    name: String,
+}
+
+#[test]
+fn json_savable_pretty() {
+    assert_eq!(
+        r#"{"name":"cdn"}"#,
+        CompactJsonData { name: "cdn".into() }.to_string().unwrap()
+    );
+    assert_eq!(
+        "{\n  \"name\": \"cdn\"\n}",
+        PrettyJsonData { name: "cdn".into() }.to_string().unwrap()
+    );
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, JsonLoadable, JsonSavable)]
+struct Wrapper<T> {
+    value: T,
+}
+
+#[test]
+fn generic_round_trip() {
+    let wrapper = Wrapper { value: vec![1, 2] };
+    let s = wrapper.to_string().unwrap();
+    assert_eq!(r#"{"value":[1,2]}"#, s);
+    assert_eq!(wrapper, Wrapper::<Vec<u32>>::from_str(&s).unwrap());
+}
+
+#[derive(CsvSavable)]
+struct CsvData {
+    name: String,
+    count: u64,
+    rate: f64,
+}
+
+#[test]
+fn csv_savable() {
+    let data = CsvData {
+        name: "cdn".into(),
+        count: 3,
+        rate: 0.5,
+    };
+    assert_eq!("name,count,rate\ncdn,3,0.5\n", data.to_string().unwrap());
+
+    let data = CsvData {
+        name: "a,\"b\"".into(),
+        count: 0,
+        rate: 1.0,
+    };
+    assert_eq!(
+        "name,count,rate\n\"a,\"\"b\"\"\",0,1\n",
+        data.to_string().unwrap()
+    );
+}
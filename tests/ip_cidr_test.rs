@@ -1,4 +1,11 @@
-use cdn_ip_tester::data::{Loadable, Subnet};
+use cidr::IpCidr;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use cdn_ip_tester::data::{
+    check_overlapping_subnets, exclude_bogon_subnets, exclude_subnets, parse_subnets,
+    random_ip_rng, range_to_cidrs, Loadable, Subnet,
+};
 
 #[test]
 fn parse_ip_cidr() {
@@ -14,5 +21,279 @@ fn parse_ip_cidr() {
     )
     .unwrap();
     println!("{subnets:?}");
-    // assert_eq!(4, subnets);
+    assert_eq!(4, subnets.len());
+    // file order is kept, the single ip is the first line
+    assert_eq!(32, subnets[0].cidr.network_length());
+    assert_eq!(1, subnets[0].len());
+
+    let subnets: Vec<Subnet> = Vec::from_str("1.2.3.4").unwrap();
+    assert_eq!(1, subnets.len());
+    assert_eq!(32, subnets[0].cidr.network_length());
+    assert_eq!(1, subnets[0].len());
+}
+
+#[test]
+fn parse_ip_cidr_strict() {
+    let input = r"192.167.2.0/24
+        1.2.3.456/24
+        ";
+    assert_eq!(1, parse_subnets(input, false).unwrap().len());
+    assert!(parse_subnets(input, true).is_err());
+}
+
+#[test]
+fn parse_ip_cidr_v6() {
+    let subnets: Vec<Subnet> = Vec::from_str(
+        r"192.167.2.0/24
+        2400:cb00:131::/48
+        2606:4700::/32
+        2606:4700::1/129
+        ",
+    )
+    .unwrap();
+    assert_eq!(3, subnets.len());
+    assert!(matches!(subnets[0].cidr, IpCidr::V4(_)));
+    assert!(matches!(subnets[1].cidr, IpCidr::V6(_)));
+    assert_eq!(32, subnets[2].cidr.network_length());
+    assert_eq!(usize::MAX, subnets[2].len());
+}
+
+#[test]
+fn parse_ip_list() {
+    let path = std::env::temp_dir().join("cdn-ip-tester-ip-list.txt");
+    std::fs::write(
+        &path,
+        "1.1.1.1\n2606:4700::1111\n\n  1.0.0.0/24  \n1.1.1.1\n",
+    )
+    .unwrap();
+    let subnets = Vec::<Subnet>::load(&path).unwrap();
+    // the duplicated 1.1.1.1 is skipped
+    assert_eq!(
+        ["1.1.1.1/32", "2606:4700::1111/128", "1.0.0.0/24"]
+            .iter()
+            .map(|cidr| cidr.parse::<IpCidr>().unwrap())
+            .collect::<Vec<_>>(),
+        subnets.iter().map(|subnet| subnet.cidr).collect::<Vec<_>>()
+    );
+    std::fs::remove_file(&path).unwrap();
+
+    // not a plain ip list, bare ipv6 ips are not searched in the text
+    let subnets = parse_subnets("ip: 2606:4700::1111\nip: 1.0.0.0/24\n", false).unwrap();
+    assert_eq!(1, subnets.len());
+}
+
+#[test]
+fn parse_ip_range() {
+    let cidrs = |cidrs: &[&str]| -> Vec<IpCidr> {
+        cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect()
+    };
+    assert_eq!(
+        cidrs(&["1.2.3.0/24"]),
+        range_to_cidrs("1.2.3.0".parse().unwrap(), "1.2.3.255".parse().unwrap())
+    );
+    assert_eq!(
+        cidrs(&["1.2.3.1/32", "1.2.3.2/31", "1.2.3.4/30", "1.2.3.8/32"]),
+        range_to_cidrs("1.2.3.1".parse().unwrap(), "1.2.3.8".parse().unwrap())
+    );
+    assert_eq!(
+        cidrs(&["0.0.0.0/0"]),
+        range_to_cidrs(
+            "0.0.0.0".parse().unwrap(),
+            "255.255.255.255".parse().unwrap()
+        )
+    );
+    assert!(range_to_cidrs("1.2.3.1".parse().unwrap(), "1.2.3.0".parse().unwrap()).is_empty());
+
+    let subnets = parse_subnets("1.2.3.0-1.2.3.255\n1.0.0.0 - 1.0.0.1\n", true).unwrap();
+    assert_eq!(
+        cidrs(&["1.2.3.0/24", "1.0.0.0/31"]),
+        subnets.iter().map(|subnet| subnet.cidr).collect::<Vec<_>>()
+    );
+
+    // mixed input keeps the order of the file
+    let subnets = parse_subnets(
+        "1.0.0.1-1.0.0.2\n2.0.0.0/24\n3.0.0.1\n2606:4700::/32\n4.0.0.0 - 4.0.0.255\n",
+        true,
+    )
+    .unwrap();
+    assert_eq!(
+        cidrs(&[
+            "1.0.0.1/32",
+            "1.0.0.2/32",
+            "2.0.0.0/24",
+            "3.0.0.1/32",
+            "2606:4700::/32",
+            "4.0.0.0/24"
+        ]),
+        subnets.iter().map(|subnet| subnet.cidr).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn split_ip_cidr() {
+    let mut subnet: Subnet = "10.0.0.0/22".parse().unwrap();
+    subnet.enable = true;
+    let subnets = subnet.split(24).unwrap();
+    assert_eq!(
+        ["10.0.0.0/24", "10.0.1.0/24", "10.0.2.0/24", "10.0.3.0/24"]
+            .iter()
+            .map(|cidr| cidr.parse::<IpCidr>().unwrap())
+            .collect::<Vec<_>>(),
+        subnets.iter().map(|subnet| subnet.cidr).collect::<Vec<_>>()
+    );
+    assert!(subnets.iter().all(|subnet| subnet.enable));
+    assert_eq!(vec![subnet.clone()], subnet.split(22).unwrap());
+    assert_eq!(vec![subnet.clone()], subnet.split(16).unwrap());
+
+    let subnet: Subnet = "2606:4700::/32".parse().unwrap();
+    let subnets = subnet.split(34).unwrap();
+    assert_eq!(4, subnets.len());
+    assert_eq!(
+        "2606:4700:c000::/34".parse::<IpCidr>().unwrap(),
+        subnets[3].cidr
+    );
+    assert_eq!(1 << 20, subnet.split(52).unwrap().len());
+    assert!(subnet.split(53).is_err());
+    assert!(subnet.split(128).is_err());
+}
+
+#[test]
+fn exclude_ip_cidr() {
+    let mut subnets: Vec<Subnet> =
+        Vec::from_str("10.0.0.0/8\n10.1.0.0/16\n192.168.1.0/24\n1.1.1.1").unwrap();
+    let excluded_subnets: Vec<Subnet> = Vec::from_str("10.1.0.0/16\n192.168.0.0/16").unwrap();
+    assert_eq!(2, exclude_subnets(&mut subnets, &excluded_subnets));
+    assert_eq!(
+        vec![
+            "10.0.0.0/8".parse::<IpCidr>().unwrap(),
+            "1.1.1.1/32".parse::<IpCidr>().unwrap()
+        ],
+        subnets.iter().map(|subnet| subnet.cidr).collect::<Vec<_>>()
+    );
+    assert!(excluded_subnets[0].contains("10.1.2.3".parse().unwrap()));
+    assert!(!excluded_subnets[0].contains("10.2.0.0".parse().unwrap()));
+}
+
+#[test]
+fn iter_ip_cidr() {
+    let subnet: Subnet = "192.168.1.0/30".parse().unwrap();
+    let ips: Vec<String> = subnet.iter().map(|ip_inet| ip_inet.to_string()).collect();
+    assert_eq!(
+        vec![
+            "192.168.1.0/30",
+            "192.168.1.1/30",
+            "192.168.1.2/30",
+            "192.168.1.3/30"
+        ],
+        ips
+    );
+    assert_eq!(subnet.get_ip(2), subnet.iter().nth(2));
+
+    let subnet: Subnet = "2001:db8::/32".parse().unwrap();
+    assert_eq!(subnet.get_ip(1000), subnet.iter().nth(1000));
+}
+
+#[test]
+fn random_ip_cidr() {
+    let subnet: Subnet = "192.168.1.0/24".parse().unwrap();
+    let mut rng = StdRng::seed_from_u64(0);
+    for _ in 0..100 {
+        assert!(subnet.contains(subnet.random_ip(&mut rng).address()));
+    }
+
+    let subnet = Subnet::new_host("1.1.1.1".parse().unwrap());
+    assert_eq!(subnet.get_ip(0).unwrap(), subnet.random_ip(&mut rng));
+}
+
+#[test]
+fn random_ip_rng_resume() {
+    let subnet: Subnet = "10.0.0.0/8".parse().unwrap();
+    let pick =
+        |seed, subnet_idx, ip_idx| subnet.random_ip(&mut random_ip_rng(seed, subnet_idx, ip_idx));
+    let ips: Vec<_> = (0..10).map(|ip_idx| pick(42, 3, ip_idx)).collect();
+    // a run resumed at ip_idx 5 picks the same ips as the uninterrupted run
+    let resumed_ips: Vec<_> = (5..10).map(|ip_idx| pick(42, 3, ip_idx)).collect();
+    assert_eq!(ips[5..], resumed_ips);
+
+    assert_ne!(ips[0], pick(43, 3, 0));
+    assert_ne!(ips[0], pick(42, 4, 0));
+    assert_ne!(ips[0], ips[1]);
+}
+
+#[test]
+fn exclude_bogon_ip_cidr() {
+    let mut subnets = parse_subnets(
+        "10.1.0.0/16\n1.1.1.0/24\n192.168.1.1\n8.0.0.0/6\nfd00::/64\n2606:4700::/32",
+        false,
+    )
+    .unwrap();
+    let bogon_subnets = exclude_bogon_subnets(&mut subnets);
+    let subnets: Vec<String> = subnets
+        .iter()
+        .map(|subnet| subnet.cidr.to_string())
+        .collect();
+    assert_eq!(vec!["1.1.1.0/24", "8.0.0.0/6", "2606:4700::/32"], subnets);
+
+    // 8.0.0.0/6 straddles 10.0.0.0/8, its bogon ips are checked one by one
+    assert!(bogon_subnets
+        .iter()
+        .any(|bogon_subnet| bogon_subnet.contains("10.0.0.1".parse().unwrap())));
+    assert!(!bogon_subnets
+        .iter()
+        .any(|bogon_subnet| bogon_subnet.contains("8.8.8.8".parse().unwrap())));
+}
+
+#[test]
+fn strided_index_ip_cidr() {
+    let subnet: Subnet = "192.168.1.0/28".parse().unwrap();
+    let idxs: Vec<usize> = (0..16).map(|idx| subnet.strided_index(idx, 4)).collect();
+    assert_eq!(vec![0, 4, 8, 12], idxs[..4]);
+    let mut sorted_idxs = idxs.clone();
+    sorted_idxs.sort_unstable();
+    assert_eq!((0..16).collect::<Vec<usize>>(), sorted_idxs);
+
+    // subnet length is not a multiple of the stride
+    let subnet: Subnet = "192.168.1.0/29".parse().unwrap();
+    let idxs: Vec<usize> = (0..8).map(|idx| subnet.strided_index(idx, 3)).collect();
+    assert_eq!(vec![0, 3, 6, 1, 4, 7, 2, 5], idxs);
+    assert_eq!(1, subnet.strided_index(1, 1));
+    assert_eq!(3, subnet.strided_index(3, 100));
+}
+
+#[test]
+fn contains_and_overlaps_ip_cidr() {
+    let subnet: Subnet = "10.0.0.0/8".parse().unwrap();
+    assert!(subnet.contains("10.255.255.255".parse().unwrap()));
+    assert!(!subnet.contains("11.0.0.0".parse().unwrap()));
+    assert!(!subnet.contains("::a00:1".parse().unwrap()));
+
+    let inner: Subnet = "10.1.0.0/16".parse().unwrap();
+    let other: Subnet = "192.168.0.0/16".parse().unwrap();
+    let v6: Subnet = "2001:db8::/32".parse().unwrap();
+    assert!(subnet.overlaps(&inner));
+    assert!(inner.overlaps(&subnet));
+    assert!(subnet.overlaps(&subnet));
+    assert!(!subnet.overlaps(&other));
+    assert!(!subnet.overlaps(&v6));
+}
+
+#[test]
+fn overlapping_ip_cidr() {
+    let input = "10.1.0.0/16\n10.0.0.0/8\n10.2.0.0/16\n192.168.1.0/24\n10.1.0.0/16\n2001:db8::/32";
+    // exact duplicates are always skipped when parsing
+    let mut subnets: Vec<Subnet> = Vec::from_str(input).unwrap();
+    assert_eq!(5, subnets.len());
+    subnets.push(subnets[3].clone());
+    assert_eq!(3, check_overlapping_subnets(&mut subnets, false));
+    assert_eq!(6, subnets.len());
+
+    assert_eq!(3, check_overlapping_subnets(&mut subnets, true));
+    assert_eq!(
+        vec!["10.0.0.0/8", "192.168.1.0/24", "2001:db8::/32"],
+        subnets
+            .iter()
+            .map(|subnet| subnet.cidr.to_string())
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(0, check_overlapping_subnets(&mut subnets, true));
 }
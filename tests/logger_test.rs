@@ -0,0 +1,34 @@
+use log::Level;
+use serde_json::{Map, Value};
+
+use cdn_ip_tester::logger::{file_log_line, json_log_line};
+
+#[test]
+fn json_log_line_fields() {
+    let mut fields = Map::new();
+    fields.insert("subnet_count".into(), 42.into());
+    fields.insert("msg".into(), "ignored".into());
+    let line: Value = serde_json::from_str(&json_log_line(
+        Level::Info,
+        1234567890,
+        "Load 42 subnets",
+        fields,
+    ))
+    .unwrap();
+    assert_eq!("INFO", line["level"]);
+    assert_eq!(1234567890, line["ts"]);
+    assert_eq!("Load 42 subnets", line["msg"]);
+    assert_eq!(42, line["subnet_count"]);
+}
+
+#[test]
+fn file_log_line_format() {
+    assert_eq!(
+        "1234567890 INFO  cdn_ip_tester: Load 42 subnets",
+        file_log_line(Level::Info, 1234567890, "cdn_ip_tester", "Load 42 subnets")
+    );
+    assert_eq!(
+        "1234567890 DEBUG cdn_ip_tester: ip: 1.2.3.4",
+        file_log_line(Level::Debug, 1234567890, "cdn_ip_tester", "ip: 1.2.3.4")
+    );
+}
@@ -0,0 +1,23 @@
+use cdn_ip_tester::cache::RttResults;
+use cdn_ip_tester::data::Loadable;
+use cdn_ip_tester::metrics::to_prometheus;
+
+#[test]
+fn metrics_to_prometheus() {
+    let rtt_results =
+        RttResults::from_str("ip: 1.2.3.4/24, server_rtt: 42, cdn_rtt: 17\n").unwrap();
+    let metrics = to_prometheus(&rtt_results, 10, 1);
+    let lines: Vec<&str> = metrics
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect();
+    assert_eq!(
+        vec![
+            r#"cdntest_ip_server_rtt_ms{ip="1.2.3.4/24"} 42"#,
+            r#"cdntest_ip_cdn_rtt_ms{ip="1.2.3.4/24"} 17"#,
+            "cdntest_total_tested 10",
+            "cdntest_total_success 1",
+        ],
+        lines
+    );
+}
@@ -0,0 +1,28 @@
+use cdn_ip_tester::progress::ProgressEvent;
+
+#[test]
+fn progress_event_json() {
+    assert_eq!(
+        r#"{"type":"start","subnets":3,"total_ips":768}"#,
+        serde_json::to_string(&ProgressEvent::Start {
+            subnets: 3,
+            total_ips: 768
+        })
+        .unwrap()
+    );
+    assert_eq!(
+        r#"{"type":"progress","done":42,"total":1000,"eta_secs":320,"last_batch_success":8,"last_batch_total":10}"#,
+        serde_json::to_string(&ProgressEvent::Progress {
+            done: 42,
+            total: 1000,
+            eta_secs: 320,
+            last_batch_success: 8,
+            last_batch_total: 10
+        })
+        .unwrap()
+    );
+    assert_eq!(
+        r#"{"type":"done","total_success":8}"#,
+        serde_json::to_string(&ProgressEvent::Done { total_success: 8 }).unwrap()
+    );
+}
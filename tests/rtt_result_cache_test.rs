@@ -0,0 +1,349 @@
+use std::time::{Duration, Instant};
+
+use cdn_ip_tester::cache::{subnet_file_hash, unix_timestamp, RttResultCache};
+use cdn_ip_tester::data::{load_subnets, Loadable, Savable};
+use cdn_ip_tester::error::ErrorKind;
+
+#[test]
+fn rtt_result_cache_expired() {
+    let mut rtt_result_cache = RttResultCache::default();
+    assert!(!rtt_result_cache.is_expired());
+
+    rtt_result_cache.cache_created_at = unix_timestamp() - 25 * 3600;
+    assert!(rtt_result_cache.is_expired());
+    rtt_result_cache.cache_ttl_hours = 48;
+    assert!(!rtt_result_cache.is_expired());
+
+    rtt_result_cache.cache_created_at = 0;
+    assert!(rtt_result_cache.is_expired());
+    rtt_result_cache.cache_ttl_hours = u64::MAX;
+    assert!(!rtt_result_cache.is_expired());
+}
+
+#[test]
+fn rtt_result_cache_legacy() {
+    let rtt_result_cache =
+        RttResultCache::from_toml("current_subnet = 1\ncurrent_subnet_start = 2\n").unwrap();
+    assert_eq!(1, rtt_result_cache.current_subnet);
+    assert_eq!(2, rtt_result_cache.current_subnet_start);
+    assert!(!rtt_result_cache.is_expired());
+    assert_eq!(None, rtt_result_cache.seed);
+    assert_eq!(1, rtt_result_cache.density);
+}
+
+#[tokio::test]
+async fn rtt_result_cache_load_or_legacy() {
+    let dir = std::env::temp_dir().join(format!("cdn-ip-tester-legacy-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("result_cache.bin");
+    let legacy_path = dir.join("result_cache.toml");
+
+    let rtt_result_cache = RttResultCache::load_or_legacy_async(&path, &legacy_path)
+        .await
+        .unwrap();
+    assert_eq!(0, rtt_result_cache.current_subnet);
+
+    std::fs::write(
+        &legacy_path,
+        "current_subnet = 1\ncurrent_subnet_start = 2\n",
+    )
+    .unwrap();
+    let mut rtt_result_cache = RttResultCache::load_or_legacy_async(&path, &legacy_path)
+        .await
+        .unwrap();
+    assert_eq!(1, rtt_result_cache.current_subnet);
+    assert_eq!(2, rtt_result_cache.current_subnet_start);
+
+    // the legacy cache is ignored once the new cache is saved
+    rtt_result_cache.current_subnet = 3;
+    rtt_result_cache.save(&path).unwrap();
+    let rtt_result_cache = RttResultCache::load_or_legacy_async(&path, &legacy_path)
+        .await
+        .unwrap();
+    assert_eq!(3, rtt_result_cache.current_subnet);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Run with `cargo test --release -- --ignored --nocapture rtt_result_cache_bincode_speed`.
+#[test]
+#[ignore]
+fn rtt_result_cache_bincode_speed() {
+    const ROUNDS: u32 = 100;
+    let rtt_result_cache = RttResultCache {
+        subnet_failure_counts: (0..10000).collect(),
+        ..Default::default()
+    };
+
+    let toml_string = toml::to_string(&rtt_result_cache).unwrap();
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let toml_string = toml::to_string(&rtt_result_cache).unwrap();
+        RttResultCache::from_toml(&toml_string).unwrap();
+    }
+    let toml_elapsed = start.elapsed() / ROUNDS;
+
+    let bincode_bytes = rtt_result_cache.to_bytes().unwrap();
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let bincode_bytes = rtt_result_cache.to_bytes().unwrap();
+        bincode::deserialize::<RttResultCache>(&bincode_bytes).unwrap();
+    }
+    let bincode_elapsed = start.elapsed() / ROUNDS;
+
+    println!(
+        "10000 entries, toml: {} bytes {toml_elapsed:?}, bincode: {} bytes {bincode_elapsed:?}",
+        toml_string.len(),
+        bincode_bytes.len()
+    );
+    assert!(bincode_elapsed < toml_elapsed);
+}
+
+#[test]
+fn rtt_result_cache_seed_round_trip() {
+    let rtt_result_cache = RttResultCache {
+        seed: Some(42),
+        ..Default::default()
+    };
+    let loaded_rtt_result_cache =
+        RttResultCache::from_str(&rtt_result_cache.to_string().unwrap()).unwrap();
+    assert_eq!(Some(42), loaded_rtt_result_cache.seed);
+}
+
+#[test]
+fn rtt_result_cache_ips_per_subnet_round_trip() {
+    assert_eq!(1, RttResultCache::default().ips_per_subnet);
+    let rtt_result_cache = RttResultCache {
+        ips_per_subnet: 25,
+        ..Default::default()
+    };
+    let loaded_rtt_result_cache =
+        RttResultCache::from_str(&rtt_result_cache.to_string().unwrap()).unwrap();
+    assert_eq!(25, loaded_rtt_result_cache.ips_per_subnet);
+    assert_eq!(1, loaded_rtt_result_cache.next_run().ips_per_subnet);
+}
+
+#[tokio::test]
+async fn rtt_result_cache_async_round_trip() {
+    let path = std::env::temp_dir().join("cdn-ip-tester-result-cache.bin");
+    let rtt_result_cache = RttResultCache {
+        current_subnet: 3,
+        current_subnet_start: 4,
+        ..Default::default()
+    };
+    rtt_result_cache.save_async(&path).await.unwrap();
+    let loaded_rtt_result_cache = RttResultCache::load_async(&path).await.unwrap();
+    assert_eq!(3, loaded_rtt_result_cache.current_subnet);
+    assert_eq!(4, loaded_rtt_result_cache.current_subnet_start);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn rtt_result_cache_subnet_file_changed() {
+    let subnet_file_path = std::env::temp_dir().join("cdn-ip-tester-subnet-file-hash.txt");
+    let cache_path = std::env::temp_dir().join("cdn-ip-tester-subnet-file-hash-cache.bin");
+    std::fs::write(&subnet_file_path, "1.1.1.0/24\n").unwrap();
+    let rtt_result_cache = RttResultCache {
+        subnet_file_hash: subnet_file_hash(&subnet_file_path).unwrap(),
+        ..Default::default()
+    };
+    rtt_result_cache.save(&cache_path).unwrap();
+    let loaded_rtt_result_cache = RttResultCache::load(&cache_path).unwrap();
+    assert!(!loaded_rtt_result_cache
+        .is_subnet_file_changed(&subnet_file_hash(&subnet_file_path).unwrap()));
+
+    std::fs::write(&subnet_file_path, "1.1.1.0/24\n1.0.0.0/24\n").unwrap();
+    rtt_result_cache.save(&cache_path).unwrap();
+    let loaded_rtt_result_cache = RttResultCache::load(&cache_path).unwrap();
+    assert!(loaded_rtt_result_cache
+        .is_subnet_file_changed(&subnet_file_hash(&subnet_file_path).unwrap()));
+
+    // caches without the hash are resumed as before
+    assert!(!RttResultCache::default()
+        .is_subnet_file_changed(&subnet_file_hash(&subnet_file_path).unwrap()));
+    std::fs::remove_file(&subnet_file_path).unwrap();
+    std::fs::remove_file(&cache_path).unwrap();
+}
+
+#[test]
+fn rtt_result_cache_check_resume() {
+    let subnet_file_path = std::env::temp_dir().join(format!(
+        "cdn-ip-tester-check-resume-{}.txt",
+        std::process::id()
+    ));
+    let ip_file = subnet_file_path.to_str().unwrap();
+    std::fs::write(&subnet_file_path, "1.1.1.0/24\n1.0.0.1\n").unwrap();
+    let subnets = load_subnets(&subnet_file_path, false).unwrap();
+    let hash = subnet_file_hash(&subnet_file_path).unwrap();
+    let rtt_result_cache = RttResultCache {
+        current_subnet: 1,
+        current_subnet_start: 5,
+        subnet_file_hash: hash.clone(),
+        ..Default::default()
+    };
+    // the /32 is skipped in the later rounds of an unchanged file
+    assert!(rtt_result_cache
+        .check_resume(&subnets, ip_file, &hash)
+        .is_ok());
+
+    std::fs::write(&subnet_file_path, "1.1.1.0/24\n1.0.0.0/24\n").unwrap();
+    let changed_hash = subnet_file_hash(&subnet_file_path).unwrap();
+    let err = rtt_result_cache
+        .check_resume(&subnets, ip_file, &changed_hash)
+        .unwrap_err();
+    assert!(matches!(*err.0, ErrorKind::Config { .. }), "{err}");
+    assert!(err.to_string().contains("changed"), "{err}");
+
+    // without the hash the current subnet must be large enough
+    let rtt_result_cache = RttResultCache {
+        subnet_file_hash: String::new(),
+        ..rtt_result_cache
+    };
+    assert!(rtt_result_cache
+        .check_resume(&subnets, ip_file, &changed_hash)
+        .is_err());
+    let rtt_result_cache = RttResultCache {
+        current_subnet: 0,
+        ..rtt_result_cache
+    };
+    assert!(rtt_result_cache
+        .check_resume(&subnets, ip_file, &changed_hash)
+        .is_ok());
+    let rtt_result_cache = RttResultCache {
+        current_subnet: 2,
+        ..rtt_result_cache
+    };
+    assert!(rtt_result_cache
+        .check_resume(&subnets, ip_file, &changed_hash)
+        .is_err());
+    std::fs::remove_file(&subnet_file_path).unwrap();
+}
+
+#[test]
+fn rtt_result_cache_touch() {
+    let mut rtt_result_cache = RttResultCache::default();
+    let mut last_touch = Instant::now() - Duration::from_millis(3500);
+    rtt_result_cache.touch(&mut last_touch);
+    assert_eq!(3, rtt_result_cache.elapsed_secs);
+    assert_ne!(0, rtt_result_cache.started_at);
+    assert!(rtt_result_cache.updated_at >= rtt_result_cache.started_at);
+    // the remaining half second is counted by the next touch
+    assert!(last_touch.elapsed() >= Duration::from_millis(500));
+
+    let started_at = rtt_result_cache.started_at;
+    last_touch -= Duration::from_secs(2);
+    rtt_result_cache.touch(&mut last_touch);
+    assert_eq!(5, rtt_result_cache.elapsed_secs);
+    assert_eq!(started_at, rtt_result_cache.started_at);
+}
+
+#[test]
+fn rtt_result_cache_circuit_breaker() {
+    let mut rtt_result_cache = RttResultCache::default();
+    assert!(!rtt_result_cache.is_subnet_tripped(3, 2));
+    for failure_count in 1..=3 {
+        assert_eq!(failure_count, rtt_result_cache.record_subnet_batch(3, true));
+    }
+    assert!(rtt_result_cache.is_subnet_tripped(3, 2));
+    assert!(!rtt_result_cache.is_subnet_tripped(0, 2));
+
+    let loaded_rtt_result_cache =
+        RttResultCache::from_str(&rtt_result_cache.to_string().unwrap()).unwrap();
+    assert!(loaded_rtt_result_cache.is_subnet_tripped(3, 2));
+
+    assert_eq!(0, rtt_result_cache.record_subnet_batch(3, false));
+    assert!(!rtt_result_cache.is_subnet_tripped(3, 2));
+}
+
+#[test]
+fn rtt_result_cache_next_run() {
+    let rtt_result_cache = RttResultCache {
+        current_subnet: 3,
+        seed: Some(42),
+        ..Default::default()
+    };
+    assert_eq!(1, rtt_result_cache.run_id);
+    let next_rtt_result_cache = rtt_result_cache.next_run();
+    assert_eq!(2, next_rtt_result_cache.run_id);
+    assert_eq!(0, next_rtt_result_cache.current_subnet);
+    assert_eq!(None, next_rtt_result_cache.seed);
+}
+
+#[test]
+fn rtt_result_cache_load_or_default() {
+    let path = std::env::temp_dir().join("cdn-ip-tester-load-or-default-cache.bin");
+    let _ = std::fs::remove_file(&path);
+    let rtt_result_cache = RttResultCache::load_or_default(&path).unwrap();
+    assert_eq!(0, rtt_result_cache.current_subnet);
+
+    std::fs::write(&path, [1, 2, 3]).unwrap();
+    assert!(RttResultCache::load_or_default(&path).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Set in the child process of [`rtt_result_cache_resume_after_killed_save`].
+const KILLED_SAVE_PATH_ENV: &str = "CDN_IP_TESTER_KILLED_SAVE_PATH";
+
+/// A child process is killed while saving the cache, the next run resumes from the last
+/// complete save. The temp file is a fifo so the child blocks in the middle of the write.
+#[cfg(unix)]
+#[test]
+fn rtt_result_cache_resume_after_killed_save() {
+    use std::ffi::CString;
+    use std::io::Read;
+    use std::os::unix::ffi::OsStrExt;
+    use std::process::{Command, Stdio};
+
+    if let Ok(path) = std::env::var(KILLED_SAVE_PATH_ENV) {
+        let mut rtt_result_cache = RttResultCache::load(&path).unwrap();
+        rtt_result_cache.current_subnet += 1;
+        // larger than the pipe buffer
+        rtt_result_cache.subnet_failure_counts = vec![1; 100_000];
+        rtt_result_cache.save(&path).unwrap();
+        return;
+    }
+
+    let dir =
+        std::env::temp_dir().join(format!("cdn-ip-tester-killed-save-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("result_cache.bin");
+    let tmp_path = path.with_extension("tmp");
+    RttResultCache {
+        current_subnet: 3,
+        current_subnet_start: 7,
+        ..Default::default()
+    }
+    .save(&path)
+    .unwrap();
+
+    let c_tmp_path = CString::new(tmp_path.as_os_str().as_bytes()).unwrap();
+    // SAFETY: c_tmp_path is a valid nul terminated path
+    assert_eq!(0, unsafe { libc::mkfifo(c_tmp_path.as_ptr(), 0o600) });
+    let mut child = Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "rtt_result_cache_resume_after_killed_save"])
+        .env(KILLED_SAVE_PATH_ENV, &path)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut half_written = vec![0; 1024];
+    std::fs::File::open(&tmp_path)
+        .unwrap()
+        .read_exact(&mut half_written)
+        .unwrap();
+    child.kill().unwrap();
+    assert!(!child.wait().unwrap().success());
+
+    // a killed process leaves a half written temp file
+    std::fs::remove_file(&tmp_path).unwrap();
+    std::fs::write(&tmp_path, &half_written).unwrap();
+
+    let mut rtt_result_cache = RttResultCache::load(&path).unwrap();
+    assert_eq!(3, rtt_result_cache.current_subnet);
+    assert_eq!(7, rtt_result_cache.current_subnet_start);
+    assert!(rtt_result_cache.subnet_failure_counts.is_empty());
+
+    rtt_result_cache.current_subnet += 1;
+    rtt_result_cache.save(&path).unwrap();
+    assert!(!tmp_path.exists());
+    assert_eq!(4, RttResultCache::load(&path).unwrap().current_subnet);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,372 @@
+use cdn_ip_tester::cache::{OutputFormat, RttResult, RttResults, ScoreWeights, SortKey};
+use cdn_ip_tester::data::{Loadable, Savable, Subnet};
+
+fn sorted_ips(rtt_results: &RttResults) -> Vec<String> {
+    rtt_results
+        .to_csv()
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|line| line.split(',').next().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn rtt_results_csv() {
+    let rtt_results = RttResults::from_str(
+        "ip: 1.2.3.4/24, server_rtt: 20, cdn_rtt: 5\nip: 2606:4700::1/32, server_rtt: 10, cdn_rtt: 7\n",
+    )
+    .unwrap();
+    let csv = rtt_results.to_csv().unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(3, lines.len());
+    assert_eq!(
+        "ip,network_length,server_rtt_ms,cdn_rtt_ms,cdn_url_used",
+        lines[0]
+    );
+    for line in &lines {
+        assert_eq!(5, line.split(',').count());
+    }
+    assert_eq!("2606:4700::1,32,10,7,", lines[1]);
+    assert_eq!("1.2.3.4,24,20,5,", lines[2]);
+
+    let rtt_results = RttResults::from_csv(&csv).unwrap();
+    assert_eq!(2, rtt_results.len());
+    assert_eq!(csv, rtt_results.to_csv().unwrap());
+
+    // csv files written before the cdn_url_used column are still readable
+    let rtt_results = RttResults::from_csv(
+        "ip,network_length,server_rtt_ms,cdn_rtt_ms\n2606:4700::1,32,10,7\n1.2.3.4,24,20,5\n",
+    )
+    .unwrap();
+    assert_eq!(csv, rtt_results.to_csv().unwrap());
+}
+
+#[test]
+fn rtt_results_jsonl() {
+    let rtt_results = RttResults::from_str(
+        "ip: 1.2.3.4/24, server_rtt: 42, cdn_rtt: 17\nip: 1.2.3.5/24, server_rtt: 50, cdn_rtt: 1\n",
+    )
+    .unwrap();
+    let jsonl = rtt_results.to_jsonl().unwrap();
+    let lines: Vec<&str> = jsonl.lines().collect();
+    assert_eq!(2, lines.len());
+    assert_eq!(r#"{"ip":"1.2.3.4/24","server_rtt":42,"cdn_rtt":17}"#, lines[0]);
+
+    let rtt_results = RttResults::from_jsonl(&jsonl).unwrap();
+    assert_eq!(2, rtt_results.len());
+    assert_eq!(jsonl, rtt_results.to_jsonl().unwrap());
+}
+
+#[test]
+fn rtt_results_sort_key() {
+    let mut rtt_results = RttResults::from_str(
+        "ip: 1.2.3.4/24, server_rtt: 10, cdn_rtt: 12\nip: 1.2.3.5/24, server_rtt: 20, cdn_rtt: 5\nip: 1.2.3.6/24, server_rtt: 15, cdn_rtt: 20\n",
+    )
+    .unwrap();
+    assert_eq!(vec!["1.2.3.4", "1.2.3.6", "1.2.3.5"], sorted_ips(&rtt_results));
+    rtt_results.sort_by_key(SortKey::CdnRtt);
+    assert_eq!(vec!["1.2.3.5", "1.2.3.4", "1.2.3.6"], sorted_ips(&rtt_results));
+    rtt_results.sort_by_key(SortKey::Sum);
+    assert_eq!(vec!["1.2.3.4", "1.2.3.5", "1.2.3.6"], sorted_ips(&rtt_results));
+}
+
+#[test]
+fn rtt_results_get_top_n() {
+    let mut rtt_results = RttResults::from_str(
+        "ip: 1.2.3.4/24, server_rtt: 10, cdn_rtt: 12\nip: 1.2.3.5/24, server_rtt: 20, cdn_rtt: 5\nip: 1.2.3.6/24, server_rtt: 15, cdn_rtt: 20\n",
+    )
+    .unwrap();
+    for n in 0..5 {
+        let top_n = rtt_results.get_top_n(n);
+        assert_eq!(n.min(rtt_results.len()), top_n.len());
+        assert!(top_n.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+    }
+    assert_eq!("1.2.3.4/24", rtt_results.get_top_n(1)[0].0.to_string());
+
+    rtt_results.truncate(2);
+    assert_eq!(2, rtt_results.len());
+    assert_eq!(vec!["1.2.3.4", "1.2.3.6"], sorted_ips(&rtt_results));
+}
+
+#[test]
+fn rtt_results_filter_by_rtt() {
+    let rtt_results = RttResults::from_str(
+        "ip: 1.2.3.4/24, server_rtt: 10, cdn_rtt: 12\nip: 1.2.3.5/24, server_rtt: 20, cdn_rtt: 5\nip: 1.2.3.6/24, server_rtt: 15, cdn_rtt: 20\n",
+    )
+    .unwrap();
+    assert_eq!(3, rtt_results.filter_by_rtt(None, None).len());
+    assert_eq!(
+        vec!["1.2.3.4", "1.2.3.6"],
+        sorted_ips(&rtt_results.filter_by_rtt(Some(15), None))
+    );
+    assert_eq!(
+        vec!["1.2.3.4", "1.2.3.5"],
+        sorted_ips(&rtt_results.filter_by_rtt(None, Some(12)))
+    );
+    assert_eq!(
+        vec!["1.2.3.4"],
+        sorted_ips(&rtt_results.filter_by_rtt(Some(15), Some(12)))
+    );
+}
+
+#[test]
+fn rtt_results_merge() {
+    let mut rtt_results = RttResults::from_str(
+        "ip: 1.2.3.4/24, server_rtt: 10, cdn_rtt: 12\nip: 1.2.3.5/24, server_rtt: 20, cdn_rtt: 5\n",
+    )
+    .unwrap();
+    let other = RttResults::from_str(
+        "ip: 1.2.3.4/24, server_rtt: 30, cdn_rtt: 1\nip: 1.2.3.5/24, server_rtt: 8, cdn_rtt: 5\nip: 1.2.3.6/24, server_rtt: 15, cdn_rtt: 20\n",
+    )
+    .unwrap();
+    rtt_results.merge(other);
+    rtt_results.commit();
+    assert_eq!(3, rtt_results.len());
+    assert_eq!(
+        vec!["1.2.3.5", "1.2.3.4", "1.2.3.6"],
+        sorted_ips(&rtt_results)
+    );
+    assert_eq!("1.2.3.4,24,10,12,", rtt_results.to_csv().unwrap().lines().nth(2).unwrap());
+}
+
+#[test]
+fn rtt_results_cdn_url_used() {
+    let s = "ip: 1.2.3.4/24, server_rtt: 10, cdn_rtt: 12, cdn_url: http://example.com/a\nip: 1.2.3.5/24, server_rtt: 20, cdn_rtt: 5\n";
+    let rtt_results = RttResults::from_str(s).unwrap();
+    assert_eq!(s, rtt_results.to_string().unwrap());
+    assert_eq!(
+        r#"{"ip":"1.2.3.4/24","server_rtt":10,"cdn_rtt":12,"cdn_url_used":"http://example.com/a"}"#,
+        rtt_results.to_jsonl().unwrap().lines().next().unwrap()
+    );
+    let csv = rtt_results.to_csv().unwrap();
+    assert_eq!(
+        "1.2.3.4,24,10,12,http://example.com/a",
+        csv.lines().nth(1).unwrap()
+    );
+    assert_eq!(s, RttResults::from_csv(&csv).unwrap().to_string().unwrap());
+}
+
+#[test]
+fn rtt_results_output_format() {
+    let s = "ip: 1.2.3.4/24, server_rtt: 10, cdn_rtt: 12\nip: 1.2.3.5/24, server_rtt: 20, cdn_rtt: 5\n";
+    let rtt_results = RttResults::from_str(s).unwrap();
+    for format in [OutputFormat::Text, OutputFormat::Csv, OutputFormat::Jsonl] {
+        let formatted = rtt_results.with_format(format).to_string().unwrap();
+        let parsed = format.parse(&formatted).unwrap();
+        assert_eq!(s, parsed.to_string().unwrap());
+    }
+    assert_eq!(
+        rtt_results.to_csv().unwrap(),
+        rtt_results.with_format(OutputFormat::Csv).to_string().unwrap()
+    );
+}
+
+#[test]
+fn rtt_results_remove_result() {
+    let mut rtt_results = RttResults::from_str(
+        "ip: 1.2.3.4/24, server_rtt: 10, cdn_rtt: 12\nip: 1.2.3.5/24, server_rtt: 20, cdn_rtt: 5\nip: 1.2.3.6/24, server_rtt: 15, cdn_rtt: 20\n",
+    )
+    .unwrap();
+    let ip_inet = "1.2.3.6/24".parse().unwrap();
+    assert!(rtt_results.remove_result(&ip_inet).is_some());
+    assert!(rtt_results.remove_result(&ip_inet).is_none());
+    assert_eq!(2, rtt_results.len());
+    assert_eq!(vec!["1.2.3.4", "1.2.3.5"], sorted_ips(&rtt_results));
+}
+
+#[test]
+fn rtt_results_replace_result() {
+    let mut rtt_results = RttResults::from_str(
+        "ip: 1.2.3.4/24, server_rtt: 10, cdn_rtt: 12\nip: 1.2.3.5/24, server_rtt: 20, cdn_rtt: 5\nip: 1.2.3.6/24, server_rtt: 15, cdn_rtt: 20\n",
+    )
+    .unwrap();
+    // the re-tested ip gets worse and moves below the untouched ones
+    rtt_results.replace_result("1.2.3.4/24".parse().unwrap(), RttResult::new(30, 12));
+    rtt_results.commit();
+    assert_eq!(vec!["1.2.3.6", "1.2.3.5", "1.2.3.4"], sorted_ips(&rtt_results));
+
+    rtt_results.replace_result("1.2.3.4/24".parse().unwrap(), RttResult::new(5, 12));
+    rtt_results.commit();
+    assert_eq!(vec!["1.2.3.4", "1.2.3.6", "1.2.3.5"], sorted_ips(&rtt_results));
+    assert_eq!(3, rtt_results.len());
+}
+
+#[test]
+fn rtt_results_iter() {
+    let mut rtt_results = RttResults::from_str(
+        "ip: 1.2.3.4/24, server_rtt: 10, cdn_rtt: 12\nip: 1.2.3.5/24, server_rtt: 20, cdn_rtt: 5\n",
+    )
+    .unwrap();
+    rtt_results.sort_by_key(SortKey::CdnRtt);
+    let res: Vec<(String, u64, u64)> = rtt_results
+        .iter()
+        .map(|(ip_inet, rtt_result)| {
+            (
+                ip_inet.to_string(),
+                rtt_result.server_rtt(),
+                rtt_result.cdn_rtt(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        vec![
+            ("1.2.3.5/24".to_string(), 20, 5),
+            ("1.2.3.4/24".to_string(), 10, 12)
+        ],
+        res
+    );
+}
+
+#[test]
+fn rtt_results_sort_by_score() {
+    let rtt_result = RttResult::new(100, 20);
+    assert_eq!(60.0, rtt_result.combined_score(0.5, 0.5));
+    assert_eq!(36.0, rtt_result.combined_score(0.2, 0.8));
+
+    let mut rtt_results = RttResults::from_str(
+        "ip: 1.2.3.4/24, server_rtt: 100, cdn_rtt: 20\nip: 1.2.3.5/24, server_rtt: 50, cdn_rtt: 80\n",
+    )
+    .unwrap();
+    rtt_results.sort_by_key(SortKey::Score);
+    assert_eq!(vec!["1.2.3.4", "1.2.3.5"], sorted_ips(&rtt_results));
+    rtt_results.set_score_weights(ScoreWeights {
+        server_weight: 0.8,
+        cdn_weight: 0.2,
+    });
+    assert_eq!(vec!["1.2.3.5", "1.2.3.4"], sorted_ips(&rtt_results));
+}
+
+#[test]
+fn rtt_result_serde() {
+    let rtt_result = RttResult::new(42, 17);
+    let json = serde_json::to_string(&rtt_result).unwrap();
+    assert_eq!(r#"{"server_rtt":42,"cdn_rtt":17}"#, json);
+    let parsed: RttResult = serde_json::from_str(&json).unwrap();
+    assert_eq!(rtt_result, parsed);
+    assert_eq!(42, parsed.server_rtt());
+    assert_eq!(17, parsed.cdn_rtt());
+
+    let rtt_result = RttResult::new(42, 17).with_cdn_url_used("http://example.com/".into());
+    let parsed: RttResult =
+        serde_json::from_str(&serde_json::to_string(&rtt_result).unwrap()).unwrap();
+    assert_eq!(Some("http://example.com/"), parsed.cdn_url_used());
+    assert!(RttResult::new(1, 2) < RttResult::new(1, 3));
+}
+
+#[test]
+fn rtt_results_resort() {
+    let mut rtt_results = RttResults::from_str(
+        "ip: 1.2.3.4/24, server_rtt: 10, cdn_rtt: 12\nip: 1.2.3.5/24, server_rtt: 20, cdn_rtt: 5\nip: 1.2.3.6/24, server_rtt: 10, cdn_rtt: 20\n",
+    )
+    .unwrap();
+    rtt_results.sort_by_key(SortKey::CdnRtt);
+    assert_eq!(
+        vec!["1.2.3.5", "1.2.3.4", "1.2.3.6"],
+        sorted_ips(&rtt_results)
+    );
+    rtt_results.sort_by_key(SortKey::ServerRtt);
+    assert_eq!(
+        vec!["1.2.3.4", "1.2.3.6", "1.2.3.5"],
+        sorted_ips(&rtt_results)
+    );
+    rtt_results.resort();
+    assert_eq!(
+        vec!["1.2.3.4", "1.2.3.6", "1.2.3.5"],
+        sorted_ips(&rtt_results)
+    );
+}
+
+#[test]
+fn rtt_results_get() {
+    let rtt_results =
+        RttResults::from_str("ip: 1.2.3.4/24, server_rtt: 10, cdn_rtt: 12\n").unwrap();
+    let rtt_result = rtt_results.get(&"1.2.3.4/24".parse().unwrap()).unwrap();
+    assert_eq!(10, rtt_result.server_rtt());
+    assert!(rtt_results.get(&"1.2.3.5/24".parse().unwrap()).is_none());
+}
+
+#[test]
+fn rtt_results_from_ips() {
+    let subnets = RttResults::from_ips(&[
+        "1.2.3.4/24".parse().unwrap(),
+        "2001:db8::1/64".parse().unwrap(),
+    ]);
+    assert_eq!(2, subnets.len());
+    assert_eq!(1, subnets[0].len());
+    let ip_inet = subnets[0].get_ip(0).unwrap();
+    assert_eq!("1.2.3.4", ip_inet.address().to_string());
+    assert_eq!(32, ip_inet.network_length());
+    assert_eq!(1, subnets[1].len());
+    let ip_inet = subnets[1].get_ip(0).unwrap();
+    assert_eq!("2001:db8::1", ip_inet.address().to_string());
+    assert_eq!(128, ip_inet.network_length());
+}
+
+#[test]
+fn rtt_result_from_samples() {
+    let rtt_result = RttResult::from_samples(&[40, 42, 50, 44], &[17]);
+    assert_eq!(40, rtt_result.server_rtt());
+    assert_eq!(17, rtt_result.cdn_rtt());
+    assert_eq!(4, rtt_result.server_jitter());
+    assert_eq!(0, rtt_result.cdn_jitter());
+}
+
+#[test]
+fn rtt_results_jitter() {
+    let s = "ip: 1.2.3.4/24, server_rtt: 42, cdn_rtt: 17, server_jitter: 5, cdn_jitter: 2\nip: 1.2.3.5/24, server_rtt: 50, cdn_rtt: 20\n";
+    let rtt_results = RttResults::from_str(s).unwrap();
+    let rtt_result = rtt_results.get(&"1.2.3.4/24".parse().unwrap()).unwrap();
+    assert_eq!(5, rtt_result.server_jitter());
+    assert_eq!(2, rtt_result.cdn_jitter());
+    assert_eq!(s, rtt_results.to_string().unwrap());
+}
+
+#[test]
+fn subnet_score() {
+    let rtt_results = RttResults::from_str(
+        "ip: 1.2.3.4/24, server_rtt: 30, cdn_rtt: 12\nip: 1.2.3.5/24, server_rtt: 20, cdn_rtt: 5\nip: 1.2.4.6/24, server_rtt: 15, cdn_rtt: 20\n",
+    )
+    .unwrap();
+    let subnet: Subnet = "1.2.3.0/24".parse().unwrap();
+    assert_eq!(Some(20), subnet.score(&rtt_results));
+    let subnet: Subnet = "1.2.0.0/16".parse().unwrap();
+    assert_eq!(Some(15), subnet.score(&rtt_results));
+    let subnet: Subnet = "1.2.5.0/24".parse().unwrap();
+    assert_eq!(None, subnet.score(&rtt_results));
+}
+
+#[test]
+fn rtt_results_grouped_string() {
+    let rtt_results = RttResults::from_str(
+        "ip: 1.2.3.4/24, server_rtt: 30, cdn_rtt: 12\nip: 1.2.4.5/24, server_rtt: 20, cdn_rtt: 5\nip: 1.2.3.6/24, server_rtt: 40, cdn_rtt: 20\nip: 5.6.7.8/24, server_rtt: 10, cdn_rtt: 1\n",
+    )
+    .unwrap();
+    let subnets: Vec<Subnet> = vec!["1.2.3.0/24".parse().unwrap(), "1.2.4.0/24".parse().unwrap()];
+    let grouped = rtt_results.to_grouped_string(&subnets).unwrap();
+    assert_eq!(
+        "=== 1.2.4.0/24 ===\nip: 1.2.4.5/24, server_rtt: 20, cdn_rtt: 5\n=== 1.2.3.0/24 ===\nip: 1.2.3.4/24, server_rtt: 30, cdn_rtt: 12\nip: 1.2.3.6/24, server_rtt: 40, cdn_rtt: 20\n=== other ===\nip: 5.6.7.8/24, server_rtt: 10, cdn_rtt: 1\n",
+        grouped
+    );
+
+    // headers are ignored when loading
+    let loaded_rtt_results = RttResults::from_str(&grouped).unwrap();
+    assert_eq!(
+        rtt_results.to_string().unwrap(),
+        loaded_rtt_results.to_string().unwrap()
+    );
+}
+
+#[test]
+fn rtt_results_add_better_result() {
+    let mut rtt_results =
+        RttResults::from_str("ip: 1.2.3.4/24, server_rtt: 10, cdn_rtt: 12\n").unwrap();
+    let ip_inet = "1.2.3.4/24".parse().unwrap();
+    rtt_results.add_better_result(ip_inet, RttResult::new(20, 5));
+    rtt_results.commit();
+    assert_eq!(10, rtt_results.get(&ip_inet).unwrap().server_rtt());
+    rtt_results.add_better_result(ip_inet, RttResult::new(5, 5));
+    rtt_results.commit();
+    assert_eq!(5, rtt_results.get(&ip_inet).unwrap().server_rtt());
+    assert_eq!(1, rtt_results.len());
+}
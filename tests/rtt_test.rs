@@ -0,0 +1,68 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::header::HeaderMap;
+use reqwest::{Client, Method, Url};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use cdn_ip_tester::config::BodyMatcher;
+use cdn_ip_tester::rtt::{do_test_rtt, retry_delay, RttTestOptions, MAX_RETRY_DELAY};
+
+#[test]
+fn rtt_retry_delay() {
+    assert_eq!(Duration::from_millis(200), retry_delay(200, 0));
+    assert_eq!(Duration::from_millis(800), retry_delay(200, 2));
+    assert_eq!(MAX_RETRY_DELAY, retry_delay(200, 20));
+    assert_eq!(MAX_RETRY_DELAY, retry_delay(200, 64));
+    assert_eq!(MAX_RETRY_DELAY, retry_delay(u64::MAX, 1));
+}
+
+/// Closes the first `failures` connections without a response, then answers `ok`.
+async fn flaky_server(failures: usize) -> (Url, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            if counter.fetch_add(1, Ordering::SeqCst) < failures {
+                continue;
+            }
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await;
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+                .await;
+        }
+    });
+    (url, connections)
+}
+
+async fn test_flaky_server(failures: usize, retry_count: u32) -> (bool, usize) {
+    let (url, connections) = flaky_server(failures).await;
+    let res = do_test_rtt(
+        Client::new(),
+        Method::GET,
+        url,
+        HeaderMap::new(),
+        RttTestOptions {
+            body_matcher: BodyMatcher::new("body", "ok", false).unwrap(),
+            expected_status: 200..=299,
+            retry_count,
+            retry_delay_ms: 1,
+            rtt_samples: 1,
+        },
+        None,
+    )
+    .await;
+    (res.is_ok(), connections.load(Ordering::SeqCst))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rtt_retry_network_error() {
+    assert_eq!((true, 3), test_flaky_server(2, 2).await);
+    assert_eq!((false, 2), test_flaky_server(2, 1).await);
+}
@@ -0,0 +1,65 @@
+use std::fs;
+
+use cdn_ip_tester::data::Savable;
+use cdn_ip_tester::error::{ErrorKind, Result};
+
+struct Content(Option<&'static str>);
+
+impl Savable for Content {
+    fn to_string(&self) -> Result<String> {
+        match self.0 {
+            Some(s) => Ok(s.into()),
+            None => Err(ErrorKind::config("serialize failed".into()))?,
+        }
+    }
+}
+
+#[test]
+fn save_atomic() {
+    let path = std::env::temp_dir().join("cdn-ip-tester-save-atomic.txt");
+    let tmp_path = path.with_extension("tmp");
+    Content(Some("old")).save(&path).unwrap();
+    assert_eq!("old", fs::read_to_string(&path).unwrap());
+    assert!(!tmp_path.exists());
+
+    // a failed write keeps the original file
+    assert!(Content(None).save(&path).is_err());
+    assert_eq!("old", fs::read_to_string(&path).unwrap());
+
+    // a stale temp file left by a killed process is replaced
+    fs::write(&tmp_path, "half writ").unwrap();
+    Content(Some("new")).save(&path).unwrap();
+    assert_eq!("new", fs::read_to_string(&path).unwrap());
+    assert!(!tmp_path.exists());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn save_backup() {
+    let path = std::env::temp_dir().join("cdn-ip-tester-save-backup.txt");
+    let backup_path = path.with_extension("bak");
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(&backup_path);
+
+    Content(Some("first")).save_backup(&path).unwrap();
+    assert!(!backup_path.exists());
+    Content(Some("second")).save_backup(&path).unwrap();
+    Content(Some("third")).save_backup(&path).unwrap();
+    assert_eq!("third", fs::read_to_string(&path).unwrap());
+    assert_eq!("second", fs::read_to_string(&backup_path).unwrap());
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&backup_path).unwrap();
+}
+
+#[tokio::test]
+async fn save_async() {
+    let path = std::env::temp_dir().join("cdn-ip-tester-save-async.txt");
+    let backup_path = path.with_extension("bak");
+    Content(Some("old")).save_async(&path).await.unwrap();
+    Content(Some("new")).save_backup_async(&path).await.unwrap();
+    assert_eq!("new", fs::read_to_string(&path).unwrap());
+    assert_eq!("old", fs::read_to_string(&backup_path).unwrap());
+    assert!(!path.with_extension("tmp").exists());
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&backup_path).unwrap();
+}
@@ -0,0 +1,78 @@
+#![cfg(unix)]
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::time::{Duration, Instant};
+
+use cdn_ip_tester::error::ErrorKind;
+use cdn_ip_tester::sing_box::SingBox;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sing_box_startup_timeout() {
+    // a fake sing box which never writes to stderr
+    let sing_box_path = std::env::temp_dir().join("cdn-ip-tester-silent-sing-box.sh");
+    fs::write(&sing_box_path, "#!/bin/sh\nexec sleep 10\n").unwrap();
+    fs::set_permissions(&sing_box_path, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let start = Instant::now();
+    let res = SingBox::new(
+        sing_box_path.to_str().unwrap(),
+        "sing-box-test-config.json",
+        &[],
+        Duration::from_millis(500),
+    )
+    .await;
+    let err = res.err().unwrap();
+    assert!(matches!(*err.0, ErrorKind::Process { .. }), "{err}");
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sing_box_shutdown() {
+    // a fake sing box which exits on SIGTERM
+    let sing_box_path = std::env::temp_dir().join("cdn-ip-tester-fake-sing-box.sh");
+    fs::write(&sing_box_path, "#!/bin/sh\necho started >&2\nexec sleep 10\n").unwrap();
+    fs::set_permissions(&sing_box_path, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let mut sing_box = SingBox::new(
+        sing_box_path.to_str().unwrap(),
+        "sing-box-test-config.json",
+        &[],
+        Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+    let start = Instant::now();
+    sing_box.shutdown().await.unwrap();
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sing_box_extra_args() {
+    // a fake sing box which exits at once without the extra arg
+    let sing_box_path = std::env::temp_dir().join(format!(
+        "cdn-ip-tester-extra-args-sing-box-{}.sh",
+        std::process::id()
+    ));
+    fs::write(
+        &sing_box_path,
+        "#!/bin/sh\necho started >&2\n[ \"$4\" = --disable-color ] && exec sleep 10\n",
+    )
+    .unwrap();
+    fs::set_permissions(&sing_box_path, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let mut sing_box = SingBox::new(
+        sing_box_path.to_str().unwrap(),
+        "sing-box-test-config.json",
+        &["--disable-color".into()],
+        Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(sing_box.is_alive());
+    sing_box.shutdown().await.unwrap();
+    assert!(!sing_box.is_alive());
+    assert!(!sing_box.crashed());
+    fs::remove_file(&sing_box_path).unwrap();
+}
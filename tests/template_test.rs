@@ -0,0 +1,122 @@
+use cdn_ip_tester::data::Loadable;
+use cdn_ip_tester::template::{Outbound, SingBoxConfig};
+
+#[test]
+fn outbound_validate() {
+    let outbound =
+        Outbound::from_str(r#"{"type": "vless", "server": "", "server_port": 443}"#).unwrap();
+    assert!(outbound.validate().is_ok());
+    let outbound = Outbound::from_str(r#"{"type": "trojan", "server_port": 443}"#).unwrap();
+    assert!(outbound.validate().is_ok());
+
+    let outbound = Outbound::from_str(r#"{"server": "example.com"}"#).unwrap();
+    assert!(outbound.validate().is_err());
+    let outbound = Outbound::from_str(r#"{"type": "unknown"}"#).unwrap();
+    assert!(outbound.validate().is_err());
+    assert!(outbound.validate_with_types(&["unknown"]).is_ok());
+    let outbound = Outbound::from_str(r#"{"type": "vmess", "server": 1}"#).unwrap();
+    assert!(outbound.validate().is_err());
+}
+
+#[test]
+fn sing_box_config_validate() {
+    let template = SingBoxConfig::from_str(
+        r#"{
+            "inbounds": [],
+            "outbounds": [{"type": "direct", "tag": "direct"}],
+            "route": {"rules": []}
+        }"#,
+    )
+    .unwrap();
+    let outbound = Outbound::from_str(r#"{"type": "vless"}"#).unwrap();
+    let ips = vec!["192.0.2.1".to_string(), "192.0.2.2".to_string()];
+    let sing_box_config = template.generate(&outbound, &ips, "127.0.0.2".into(), 31000);
+    assert!(sing_box_config.validate().is_ok());
+
+    // generating twice reuses the same tags
+    let sing_box_config = sing_box_config.generate(&outbound, &ips, "127.0.0.2".into(), 31002);
+    assert!(sing_box_config.validate().is_err());
+
+    let template = SingBoxConfig::from_str(
+        r#"{
+            "inbounds": [],
+            "outbounds": [{"type": "direct", "tag": "direct"}],
+            "route": {"rules": [{"inbound": "missing", "outbound": "direct"}]}
+        }"#,
+    )
+    .unwrap();
+    assert!(template.validate().is_err());
+
+    let template = SingBoxConfig::from_str(
+        r#"{
+            "inbounds": [],
+            "outbounds": [],
+            "route": {"rules": [{"outbound": "direct"}]}
+        }"#,
+    )
+    .unwrap();
+    assert!(template.validate().is_err());
+}
+
+#[test]
+fn sing_box_config_checksum() {
+    let template = SingBoxConfig::from_str(
+        r#"{
+            "log": {"level": "warn", "timestamp": true},
+            "inbounds": [],
+            "outbounds": [{"type": "direct", "tag": "direct"}],
+            "route": {"rules": [], "final": "direct"}
+        }"#,
+    )
+    .unwrap();
+    let outbound = Outbound::from_str(r#"{"type": "vless", "server_port": 443}"#).unwrap();
+    let ips = vec!["192.0.2.1".to_string(), "192.0.2.2".to_string()];
+    let sing_box_config = template.generate(&outbound, &ips, "127.0.0.2".into(), 31000);
+
+    // the same config generated again has the same checksum regardless of the key order
+    let same_sing_box_config = template.generate(&outbound, &ips, "127.0.0.2".into(), 31000);
+    assert_eq!(sing_box_config.checksum(), same_sing_box_config.checksum());
+
+    let ips = vec!["192.0.2.1".to_string(), "192.0.2.3".to_string()];
+    let other_sing_box_config = template.generate(&outbound, &ips, "127.0.0.2".into(), 31000);
+    assert_ne!(sing_box_config.checksum(), other_sing_box_config.checksum());
+}
+
+#[test]
+fn outbound_builders() {
+    let outbound = Outbound::from_str(r#"{"type": "vless", "server_port": 443}"#)
+        .unwrap()
+        .with_tls_config("example.com", true)
+        .with_multiplex(true, 8)
+        .with_transport("ws");
+    let value = serde_json::to_value(&outbound).unwrap();
+    assert_eq!(
+        serde_json::json!({"enabled": true, "server_name": "example.com", "insecure": true}),
+        value["tls"]
+    );
+    assert_eq!(
+        serde_json::json!({"enabled": true, "max_streams": 8}),
+        value["multiplex"]
+    );
+    assert_eq!(serde_json::json!({"type": "ws"}), value["transport"]);
+    assert!(outbound.validate().is_ok());
+}
+
+#[test]
+fn sing_box_config_dns_server() {
+    let template = SingBoxConfig::from_str(
+        r#"{
+            "inbounds": [],
+            "outbounds": [{"type": "direct", "tag": "direct"}],
+            "route": {"rules": []}
+        }"#,
+    )
+    .unwrap()
+    .with_dns_server("tls://1.1.1.1");
+    let outbound = Outbound::from_str(r#"{"type": "vless"}"#).unwrap();
+    let sing_box_config =
+        template.generate(&outbound, &["192.0.2.1".into()], "127.0.0.2".into(), 31000);
+    let value = serde_json::to_value(&sing_box_config).unwrap();
+    assert_eq!("tls://1.1.1.1", value["dns"]["servers"][0]["address"]);
+    assert_eq!("dns-server", value["dns"]["final"]);
+}